@@ -0,0 +1,310 @@
+// Records the screen as an animated PNG, a GIF or a raw stream of RGB frames.
+// Frames are handed over once per emulated frame, so the timing in the file
+// follows the machine's refresh rate rather than the host's.
+use std::collections::HashMap;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use serde::{Serialize, Deserialize};
+
+pub const FRAME_RATE: u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureFormat {
+    Apng,
+    Gif,
+    // 24 bit RGB frames back to back with no header, e.g. for ffmpeg
+    Raw
+}
+
+impl CaptureFormat {
+    pub fn from_path(path: &Path) -> Result<CaptureFormat, String> {
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        match extension.as_deref() {
+            Some("png") | Some("apng") => Ok(CaptureFormat::Apng),
+            Some("gif") => Ok(CaptureFormat::Gif),
+            Some("rgb") | Some("raw") => Ok(CaptureFormat::Raw),
+            _ => Err(format!("{}: unknown capture format, use .png, .gif or .rgb", path.display()))
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CaptureFormat::Apng => "png",
+            CaptureFormat::Gif => "gif",
+            CaptureFormat::Raw => "rgb"
+        }
+    }
+}
+
+enum Output {
+    Apng(ApngWriter<BufWriter<File>>),
+    Gif(GifWriter),
+    Raw(BufWriter<File>)
+}
+
+pub struct FrameRecorder {
+    output: Output,
+    width: usize,
+    height: usize
+}
+
+impl FrameRecorder {
+    // Picks the format from the extension of `path`.
+    pub fn create(path: &Path, width: usize, height: usize) -> Result<FrameRecorder, String> {
+        let format = CaptureFormat::from_path(path)?;
+        let file = BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?);
+        let output = match format {
+            CaptureFormat::Apng => Output::Apng(ApngWriter::new(file, width as u32, height as u32).map_err(|e| e.to_string())?),
+            CaptureFormat::Gif => Output::Gif(GifWriter::new(file, width as u16, height as u16)?),
+            CaptureFormat::Raw => Output::Raw(file)
+        };
+        Ok(FrameRecorder {
+            output,
+            width,
+            height
+        })
+    }
+
+    // `rgb` is one frame, 3 bytes per pixel, row by row from the top left.
+    pub fn write_frame(&mut self, rgb: &[u8]) -> Result<(), String> {
+        if rgb.len() != self.width * self.height * 3 {
+            return Err(format!("expected a {}x{} frame", self.width, self.height));
+        }
+        match &mut self.output {
+            Output::Apng(apng) => apng.write_frame(rgb).map_err(|e| e.to_string()),
+            Output::Gif(gif) => gif.write_frame(rgb),
+            Output::Raw(raw) => raw.write_all(rgb).map_err(|e| e.to_string())
+        }
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        match self.output {
+            Output::Apng(mut apng) => apng.finish().map_err(|e| e.to_string()),
+            // the trailer is written when the encoder is dropped
+            Output::Gif(gif) => {
+                drop(gif);
+                Ok(())
+            }
+            Output::Raw(mut raw) => raw.flush().map_err(|e| e.to_string())
+        }
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// signature and IHDR come first, so acTL always starts here
+const ACTL_OFFSET: u64 = 8 + 12 + 13;
+
+// Truecolour APNG. The number of frames isn't known until the end, so the
+// animation control chunk is rewritten by `finish`, the same way the WAV
+// writer patches its sizes.
+pub struct ApngWriter<W: Write + Seek> {
+    out: W,
+    width: u32,
+    height: u32,
+    frames: u32,
+    sequence: u32,
+    finished: bool
+}
+
+impl<W: Write + Seek> ApngWriter<W> {
+    pub fn new(mut out: W, width: u32, height: u32) -> io::Result<Self> {
+        out.write_all(&PNG_SIGNATURE)?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        // 8 bit RGB, deflate, no interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut out, b"IHDR", &header)?;
+        write_chunk(&mut out, b"acTL", &actl(0))?;
+        Ok(ApngWriter {
+            out,
+            width,
+            height,
+            frames: 0,
+            sequence: 0,
+            finished: false
+        })
+    }
+
+    pub fn write_frame(&mut self, rgb: &[u8]) -> io::Result<()> {
+        let mut control = Vec::new();
+        control.extend_from_slice(&self.sequence.to_be_bytes());
+        control.extend_from_slice(&self.width.to_be_bytes());
+        control.extend_from_slice(&self.height.to_be_bytes());
+        control.extend_from_slice(&0u32.to_be_bytes());
+        control.extend_from_slice(&0u32.to_be_bytes());
+        control.extend_from_slice(&1u16.to_be_bytes());
+        control.extend_from_slice(&(FRAME_RATE as u16).to_be_bytes());
+        // no disposal, replace the previous frame
+        control.extend_from_slice(&[0, 0]);
+        write_chunk(&mut self.out, b"fcTL", &control)?;
+        self.sequence += 1;
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        for row in rgb.chunks(self.width as usize * 3) {
+            // filter type none
+            encoder.write_all(&[0])?;
+            encoder.write_all(row)?;
+        }
+        let data = encoder.finish()?;
+        if self.frames == 0 {
+            write_chunk(&mut self.out, b"IDAT", &data)?;
+        } else {
+            let mut frame_data = self.sequence.to_be_bytes().to_vec();
+            frame_data.extend_from_slice(&data);
+            write_chunk(&mut self.out, b"fdAT", &frame_data)?;
+            self.sequence += 1;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    // A PNG needs image data, so a recording without frames gets a black one.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        if self.frames == 0 {
+            let black = vec![0; self.width as usize * self.height as usize * 3];
+            self.write_frame(&black)?;
+        }
+        write_chunk(&mut self.out, b"IEND", &[])?;
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(ACTL_OFFSET))?;
+        write_chunk(&mut self.out, b"acTL", &actl(self.frames))?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.finished = true;
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+}
+
+impl<W: Write + Seek> Drop for ApngWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            println!("Error finishing PNG file {}", e);
+        }
+    }
+}
+
+// frame count, then 0 plays to loop forever
+fn actl(frames: u32) -> Vec<u8> {
+    let mut data = frames.to_be_bytes().to_vec();
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc.finalize().to_be_bytes())
+}
+
+// Browsers slow down GIFs with delays under 2/100s, so only every other frame
+// is kept and the delays alternate to average out at the emulated rate.
+struct GifWriter {
+    encoder: gif::Encoder<BufWriter<File>>,
+    width: u16,
+    height: u16,
+    frames: u64
+}
+
+impl GifWriter {
+    fn new(out: BufWriter<File>, width: u16, height: u16) -> Result<Self, String> {
+        let mut encoder = gif::Encoder::new(out, width, height, &[]).map_err(|e| e.to_string())?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| e.to_string())?;
+        Ok(GifWriter {
+            encoder,
+            width,
+            height,
+            frames: 0
+        })
+    }
+
+    fn write_frame(&mut self, rgb: &[u8]) -> Result<(), String> {
+        let frame_number = self.frames;
+        self.frames += 1;
+        if frame_number % 2 == 1 {
+            return Ok(());
+        }
+        // centiseconds from the start of the recording to this frame and the next kept one
+        let start = frame_number * 100 / FRAME_RATE as u64;
+        let end = (frame_number + 2) * 100 / FRAME_RATE as u64;
+        let mut frame = match palette_frame(self.width, self.height, rgb) {
+            Some(frame) => frame,
+            None => gif::Frame::from_rgb_speed(self.width, self.height, rgb, 10)
+        };
+        frame.delay = (end - start) as u16;
+        self.encoder.write_frame(&frame).map_err(|e| e.to_string())
+    }
+}
+
+// The screen only ever shows a handful of colours, so an exact palette is
+// built rather than quantizing. None if there are more than 256.
+fn palette_frame(width: u16, height: u16, rgb: &[u8]) -> Option<gif::Frame<'static>> {
+    let mut colours: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let mut indices = Vec::with_capacity(rgb.len() / 3);
+    for pixel in rgb.chunks_exact(3) {
+        let colour = [pixel[0], pixel[1], pixel[2]];
+        let index = match colours.get(&colour) {
+            Some(index) => *index,
+            None => {
+                if colours.len() == 256 {
+                    return None;
+                }
+                let index = colours.len() as u8;
+                colours.insert(colour, index);
+                palette.extend_from_slice(&colour);
+                index
+            }
+        };
+        indices.push(index);
+    }
+    Some(gif::Frame {
+        width,
+        height,
+        buffer: Cow::Owned(indices),
+        palette: Some(palette),
+        ..gif::Frame::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use crate::capture::ApngWriter;
+
+    #[test]
+    fn apng_frame_count_is_patched() {
+        let mut writer = ApngWriter::new(Cursor::new(Vec::new()), 2, 1).unwrap();
+        writer.write_frame(&[255, 0, 0, 0, 255, 0]).unwrap();
+        writer.write_frame(&[0, 0, 255, 255, 255, 255]).unwrap();
+        writer.write_frame(&[0, 0, 0, 0, 0, 0]).unwrap();
+        writer.finish().unwrap();
+        let bytes = writer.get_ref().get_ref().clone();
+        let decoder = png::Decoder::new(Cursor::new(bytes));
+        let mut reader = decoder.read_info().unwrap();
+        let control = reader.info().animation_control.unwrap();
+        assert_eq!(control.num_frames, 3);
+        let mut frame = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut frame).unwrap();
+        assert_eq!(frame, vec![255, 0, 0, 0, 255, 0]);
+        reader.next_frame(&mut frame).unwrap();
+        assert_eq!(frame, vec![0, 0, 255, 255, 255, 255]);
+    }
+}
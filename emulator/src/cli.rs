@@ -0,0 +1,199 @@
+// The command line: an optional command followed by flags in any order,
+// e.g. `emulator run --scale 3 --paused`.
+use std::path::PathBuf;
+use emulator::config::DEFAULT_CONFIG_PATH;
+use emulator::frontends::Options;
+
+pub const USAGE: &str = "\
+Usage: emulator [COMMAND] [OPTIONS]
+
+Commands:
+  run                  play in a window, or in the terminal with --tui (the default)
+  headless             run for --frames frames with nothing shown or played
+  machines             list the machines that can be run
+  help                 show this message
+
+Options:
+  --machine NAME       the machine to run, e.g. spaceinvaders
+  --rom PATH           the ROM file, or a directory holding it or its chips
+  --config PATH        the config file, eightyeighty.toml by default
+  --scale N            window size as a multiple of the screen
+  --fullscreen         start fullscreen
+  --mute               no sound
+  --lives N            DIP switch: ships per game, 3 to 6
+  --bonus-life N       DIP switch: score for the extra ship, 1000 or 1500
+  --no-coin-info       DIP switch: hide the coin info on the attract screen
+  --paused             start paused, P carries on
+  --tui                play in the terminal
+  --load-state PATH    start from a save state, F5 and F7 save and load it again
+  --save-state PATH    save the state after the last headless frame
+  --record-input PATH  write down the buttons pressed on each frame
+  --replay-input PATH  play back buttons written down with --record-input
+  --record-audio PATH  record the sound to a WAV file
+  --record-video PATH  record the screen to a .png, .gif or .rgb file
+  --frames N           how many frames to run headless
+  -v, --verbose        say what is being loaded
+  --trace              print every CPU op to stderr
+  -h, --help           show this message
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Run,
+    Headless,
+    Machines,
+    Help
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    pub config: PathBuf,
+    // these override the config
+    pub machine: Option<String>,
+    pub rom: Option<PathBuf>,
+    pub scale: Option<u32>,
+    pub fullscreen: bool,
+    pub mute: bool,
+    pub lives: Option<u8>,
+    pub bonus_life: Option<u16>,
+    pub coin_info: Option<bool>,
+    pub tui: bool,
+    pub frames: Option<u64>,
+    pub save_state: Option<PathBuf>,
+    pub verbose: bool,
+    pub options: Options
+}
+
+impl Cli {
+    // `args` without the program name.
+    pub fn parse(args: &[String]) -> Result<Cli, String> {
+        let mut args = args.iter().peekable();
+        let command = match args.peek().map(|arg| arg.as_str()) {
+            Some("run") => Command::Run,
+            Some("headless") => Command::Headless,
+            Some("machines") => Command::Machines,
+            Some("help") => Command::Help,
+            Some(arg) if !arg.starts_with('-') => {
+                return Err(format!("unknown command {}, expected run, headless, machines or help", arg));
+            }
+            _ => Command::Run
+        };
+        if args.peek().is_some_and(|arg| !arg.starts_with('-')) {
+            args.next();
+        }
+        let mut cli = Cli {
+            command,
+            config: PathBuf::from(DEFAULT_CONFIG_PATH),
+            machine: None,
+            rom: None,
+            scale: None,
+            fullscreen: false,
+            mute: false,
+            lives: None,
+            bonus_life: None,
+            coin_info: None,
+            tui: false,
+            frames: None,
+            save_state: None,
+            verbose: false,
+            options: Options::default()
+        };
+        let mut given = Vec::new();
+        while let Some(flag) = args.next() {
+            given.push(flag.as_str());
+            let mut value = || args.next().cloned().ok_or_else(|| format!("{} needs a value", flag));
+            match flag.as_str() {
+                "--machine" => cli.machine = Some(value()?),
+                "--rom" => cli.rom = Some(PathBuf::from(value()?)),
+                "--config" => cli.config = PathBuf::from(value()?),
+                "--scale" => cli.scale = Some(positive(flag, &value()?)? as u32),
+                "--fullscreen" => cli.fullscreen = true,
+                "--mute" => cli.mute = true,
+                "--lives" => cli.lives = Some(number(flag, &value()?)?),
+                "--bonus-life" => cli.bonus_life = Some(number(flag, &value()?)?),
+                "--no-coin-info" => cli.coin_info = Some(false),
+                "--paused" => cli.options.paused = true,
+                "--tui" => cli.tui = true,
+                "--load-state" => cli.options.state = Some(PathBuf::from(value()?)),
+                "--save-state" => cli.save_state = Some(PathBuf::from(value()?)),
+                "--record-input" => cli.options.record_input = Some(PathBuf::from(value()?)),
+                "--replay-input" => cli.options.replay_input = Some(PathBuf::from(value()?)),
+                "--record-audio" => cli.options.record_audio = Some(PathBuf::from(value()?)),
+                "--record-video" => cli.options.record_video = Some(PathBuf::from(value()?)),
+                "--frames" => cli.frames = Some(positive(flag, &value()?)?),
+                "-v" | "--verbose" => cli.verbose = true,
+                "--trace" => cli.options.trace = true,
+                "-h" | "--help" => cli.command = Command::Help,
+                _ => return Err(format!("unknown option {}, see --help", flag))
+            }
+        }
+        // flags the command would ignore are mistakes, e.g. `headless --tui`
+        let ignored: &[&str] = match cli.command {
+            Command::Headless => &["--tui", "--scale", "--fullscreen", "--paused"],
+            Command::Run if cli.tui => &["--scale", "--fullscreen"],
+            _ => &[]
+        };
+        if let Some(flag) = given.iter().find(|flag| ignored.contains(flag)) {
+            return Err(match cli.command {
+                Command::Headless => format!("{} doesn't work headless", flag),
+                _ => format!("{} doesn't work with --tui", flag)
+            });
+        }
+        match cli.command {
+            Command::Machines if !given.is_empty() => Err(format!("machines takes no options, got {}", given[0])),
+            Command::Headless if cli.frames.is_none() => Err("headless needs --frames N".to_string()),
+            Command::Run if cli.frames.is_some() || cli.save_state.is_some() => {
+                Err("--frames and --save-state only work headless".to_string())
+            }
+            _ => Ok(cli)
+        }
+    }
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} needs a number, got {}", flag, value))
+}
+
+fn positive(flag: &str, value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(number) if number > 0 && number <= u32::MAX as u64 => Ok(number),
+        _ => Err(format!("{} needs a whole number of at least 1, got {}", flag, value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use crate::cli::{Cli, Command};
+
+    fn parse(args: &str) -> Result<Cli, String> {
+        let args: Vec<String> = args.split_whitespace().map(|arg| arg.to_string()).collect();
+        Cli::parse(&args)
+    }
+
+    #[test]
+    fn commands_and_flags() {
+        let cli = parse("--scale 2 --paused").unwrap();
+        assert_eq!(cli.command, Command::Run);
+        assert_eq!(cli.scale, Some(2));
+        assert!(cli.options.paused);
+
+        let cli = parse("headless --frames 600 --replay-input game.txt --save-state end.state").unwrap();
+        assert_eq!(cli.command, Command::Headless);
+        assert_eq!(cli.frames, Some(600));
+        assert_eq!(cli.options.replay_input, Some(PathBuf::from("game.txt")));
+
+        assert_eq!(parse("headless --frames 1 --tui").err(), Some("--tui doesn't work headless".to_string()));
+        assert_eq!(parse("--tui --scale 2").err(), Some("--scale doesn't work with --tui".to_string()));
+        assert_eq!(parse("machines --rom invaders").err(), Some("machines takes no options, got --rom".to_string()));
+        assert_eq!(parse("headless --tui --help").map(|cli| cli.command), Ok(Command::Help));
+        assert_eq!(parse("headless").err(), Some("headless needs --frames N".to_string()));
+        assert_eq!(parse("--scale 0").err(), Some("--scale needs a whole number of at least 1, got 0".to_string()));
+        assert_eq!(parse("--rom").err(), Some("--rom needs a value".to_string()));
+        assert_eq!(parse("--lives 5 --no-coin-info").map(|cli| (cli.lives, cli.coin_info)), Ok((Some(5), Some(false))));
+        assert_eq!(parse("--lives many").err(), Some("--lives needs a number, got many".to_string()));
+        assert_eq!(parse("play").err(), Some("unknown command play, expected run, headless, machines or help".to_string()));
+        assert_eq!(parse("--loud").err(), Some("unknown option --loud, see --help".to_string()));
+    }
+}
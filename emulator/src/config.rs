@@ -0,0 +1,197 @@
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use crate::input::{KeyBinding, HotkeyBinding, default_key_bindings, default_hotkey_bindings};
+use crate::capture::CaptureFormat;
+use crate::filters::Filter;
+use crate::overlay::Overlay;
+use crate::machines::{Orientation, Cabinet, Monitor, MachineInfo, DipSwitches, SoundFiles, find_machine};
+
+pub const DEFAULT_CONFIG_PATH: &str = "eightyeighty.toml";
+pub const DEFAULT_MACHINE: &str = "spaceinvaders";
+
+// How the screen fills a window that isn't an exact multiple of its size.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scaling {
+    // whole multiples only, for even pixels
+    Integer,
+    // as large as fits, letterboxed to keep the aspect ratio
+    Aspect,
+    // fill the window
+    Stretch
+}
+
+// Characters the terminal frontend draws with.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextMode {
+    // 2x4 pixels a character, 112x64 characters for the whole screen
+    Braille,
+    // 1x2 pixels a character, 224x128 characters, but in full colour
+    Blocks
+}
+
+// Where the sound comes from: the WAV samples or the built-in synthesizer.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SoundSource {
+    Samples,
+    Synth
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // which machine to run, e.g. spaceinvaders
+    pub machine: String,
+    // a ROM file or directory, the machine's own from its resources if unset
+    pub rom: Option<String>,
+    // initial window size as a multiple of the screen, reduced to fit the display
+    pub scale: u32,
+    pub scaling: Scaling,
+    pub fullscreen: bool,
+    // applied in order, e.g. ["phosphor", "scale2x", "scanlines"]
+    pub filters: Vec<Filter>,
+    pub orientation: Orientation,
+    pub mirror: bool,
+    pub cabinet: Cabinet,
+    pub text_mode: TextMode,
+    // 0 (silent) to 100 (full volume)
+    pub volume: u8,
+    // name of a built in overlay or one from `overlays`
+    pub overlay: String,
+    // synth (built in, needs no files) or samples (the WAV files in `sounds`)
+    pub sound: SoundSource,
+    // what F10 records to: png (animated), gif or rgb (raw frames)
+    pub video_format: CaptureFormat,
+    // tables have to come after the plain values for the toml serializer,
+    // these two are keyed by the machine's DIP switch and sound names
+    pub dip_switches: DipSwitches,
+    pub sounds: SoundFiles,
+    pub bindings: Vec<KeyBinding>,
+    pub hotkeys: Vec<HotkeyBinding>,
+    // an empty array would be written as a plain value after the tables
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub overlays: Vec<Overlay>
+}
+
+impl Default for Config {
+    // Every setting of the default machine written out, so there is
+    // something to edit.
+    fn default() -> Self {
+        let info = find_machine(DEFAULT_MACHINE).unwrap();
+        Config {
+            machine: DEFAULT_MACHINE.to_string(),
+            rom: None,
+            scale: 4,
+            scaling: Scaling::Aspect,
+            fullscreen: false,
+            filters: Vec::new(),
+            orientation: Orientation::Upright,
+            mirror: false,
+            cabinet: Cabinet::Upright,
+            text_mode: TextMode::Braille,
+            volume: 100,
+            overlay: info.overlays[0].name.clone(),
+            sound: SoundSource::Synth,
+            video_format: CaptureFormat::Apng,
+            dip_switches: info.default_dip_switches(),
+            sounds: info.default_sounds(),
+            bindings: default_key_bindings(),
+            hotkeys: default_hotkey_bindings(),
+            overlays: Vec::new()
+        }
+    }
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        if config.scale == 0 {
+            return Err("scale must be at least 1".to_string());
+        }
+        if config.volume > 100 {
+            return Err(format!("volume must be between 0 and 100, got {}", config.volume));
+        }
+        let info = find_machine(&config.machine)?;
+        info.check_dip_switches(&config.dip_switches)?;
+        info.check_sounds(&config.sounds)?;
+        config.find_overlay()?;
+        Ok(config)
+    }
+
+    pub fn monitor(&self, info: &MachineInfo) -> Monitor {
+        Monitor {
+            orientation: self.orientation,
+            mirror: self.mirror,
+            cabinet: self.cabinet,
+            ..Monitor::new(info)
+        }
+    }
+
+    pub fn find_overlay(&self) -> Result<Overlay, String> {
+        Overlay::find(&self.overlay, &self.overlays, &find_machine(&self.machine)?.overlays)
+    }
+
+    // The overlay turned and mirrored with the picture, for the frontends to
+    // put in front of the machine's screen.
+    pub fn screen_overlay(&self) -> Result<Overlay, String> {
+        let info = find_machine(&self.machine)?;
+        Ok(self.monitor(&info).place_overlay(&self.find_overlay()?))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap()
+    }
+
+    // Reads the config at `path`, writing out the defaults first if the file
+    // doesn't exist yet so there is something to edit.
+    pub fn load_or_create(path: &Path) -> Result<Config, String> {
+        if !path.exists() {
+            let config = Config::default();
+            fs::write(path, config.to_toml()).map_err(|e| format!("{}: {}", path.display(), e))?;
+            return Ok(config);
+        }
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Config::from_toml(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+
+    #[test]
+    fn default_config_round_trips() {
+        let config = Config::default();
+        assert_eq!(Config::from_toml(&config.to_toml()), Ok(config));
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let config = Config::from_toml("scale = 2").unwrap();
+        assert_eq!(config.scale, 2);
+        assert_eq!(config.bindings, Config::default().bindings);
+    }
+
+    #[test]
+    fn custom_overlay() {
+        let text = "overlay = \"mine\"\n[[overlays]]\nname = \"mine\"\ncolor = [0, 255, 0]\n[[overlays.gels]]\nx = 0\ny = 0\nwidth = 224\nheight = 8\ncolor = [255, 0, 0]\n";
+        let config = Config::from_toml(text).unwrap();
+        assert_eq!(config.find_overlay().unwrap().color, (0, 255, 0));
+        assert_eq!(config.find_overlay().unwrap().color_at(10, 4), (255, 0, 0));
+        assert_eq!(Config::from_toml(&config.to_toml()), Ok(config));
+        assert!(Config::from_toml("overlay = \"missing\"").is_err());
+    }
+
+    #[test]
+    fn invalid_dip_switches_are_rejected() {
+        let result = Config::from_toml("[dip_switches]\nlives = 7");
+        assert_eq!(result, Err("lives must be one of 3, 4, 5, 6, got 7".to_string()));
+        let result = Config::from_toml("[dip_switches]\nlives = 4\ncoin_info = 1");
+        assert_eq!(result, Err("coin_info must be one of true, false, got 1".to_string()));
+        let result = Config::from_toml("[sounds]\nlaser = \"laser.wav\"");
+        assert_eq!(result, Err("Space Invaders has no sound laser".to_string()));
+    }
+}
@@ -0,0 +1,207 @@
+use std::fs;
+use std::path::Path;
+use crate::cpu::MEMORY_SIZE;
+
+// CP/M loads transient programs at the start of the TPA and they exit by
+// jumping to the warm boot vector at 0x0000.
+const COM_ORIGIN: usize = 0x0100;
+const CPM_WARM_BOOT: usize = 0x0000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HaltCondition {
+  // stop once a HLT instruction has been executed
+  Hlt,
+  // stop when the program counter reaches the given address
+  Address(usize)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+  pub origin: usize,
+  pub bytes: Vec<u8>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+  pub segments: Vec<Segment>,
+  pub entry: usize,
+  pub halt_condition: HaltCondition
+}
+
+impl Program {
+  // A flat binary image copied to memory at `origin`, starting execution there.
+  pub fn raw(bytes: Vec<u8>, origin: usize) -> Result<Program, String> {
+    check_bounds(origin, bytes.len())?;
+    Ok(Program {
+      segments: vec![Segment { origin, bytes }],
+      entry: origin,
+      halt_condition: HaltCondition::Hlt
+    })
+  }
+
+  // A CP/M .COM file, loaded and started at 0x0100, which halts on warm boot.
+  pub fn com(bytes: Vec<u8>) -> Result<Program, String> {
+    let mut program = Program::raw(bytes, COM_ORIGIN)?;
+    program.halt_condition = HaltCondition::Address(CPM_WARM_BOOT);
+    Ok(program)
+  }
+
+  pub fn from_intel_hex(text: &str) -> Result<Program, String> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut base = 0;
+    let mut entry = None;
+    let mut seen_eof = false;
+    for (n, line) in text.lines().enumerate() {
+      let line_num = n + 1;
+      let line = line.trim();
+      if line.is_empty() {
+        continue;
+      }
+      if seen_eof {
+        return Err(format!("line {}: record after end of file record", line_num));
+      }
+      let record = parse_record(line).map_err(|e| format!("line {}: {}", line_num, e))?;
+      let len = record[0] as usize;
+      let offset = u16::from_be_bytes([record[1], record[2]]) as usize;
+      let data = &record[4..4 + len];
+      match record[3] {
+        // data
+        0x00 => {
+          let address = base + offset;
+          check_bounds(address, len).map_err(|e| format!("line {}: {}", line_num, e))?;
+          match segments.last_mut() {
+            Some(segment) if segment.origin + segment.bytes.len() == address => {
+              segment.bytes.extend_from_slice(data);
+            }
+            _ => segments.push(Segment { origin: address, bytes: data.to_vec() })
+          }
+        }
+        // end of file
+        0x01 => {
+          seen_eof = true;
+        }
+        // extended segment address
+        0x02 => {
+          expect_len(len, 2, line_num)?;
+          base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 4;
+        }
+        // start segment address (CS:IP)
+        0x03 => {
+          expect_len(len, 4, line_num)?;
+          let cs = u16::from_be_bytes([data[0], data[1]]) as usize;
+          let ip = u16::from_be_bytes([data[2], data[3]]) as usize;
+          entry = Some((cs << 4) + ip);
+        }
+        // extended linear address
+        0x04 => {
+          expect_len(len, 2, line_num)?;
+          base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 16;
+        }
+        // start linear address
+        0x05 => {
+          expect_len(len, 4, line_num)?;
+          entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize);
+        }
+        record_type => {
+          return Err(format!("line {}: unknown record type {:02x}", line_num, record_type));
+        }
+      }
+    }
+    if !seen_eof {
+      return Err("missing end of file record".to_string());
+    }
+    if segments.is_empty() {
+      return Err("no data records".to_string());
+    }
+    let entry = entry.unwrap_or(segments[0].origin);
+    if entry >= MEMORY_SIZE {
+      return Err(format!("start address {:04x} is outside of memory", entry));
+    }
+    Ok(Program {
+      segments,
+      entry,
+      halt_condition: HaltCondition::Hlt
+    })
+  }
+
+  // Picks a loader from the file extension: `.hex`/`.ihx` as Intel HEX,
+  // `.com` as a CP/M program and anything else as a raw image at 0x0000.
+  pub fn from_path(path: &Path) -> Result<Program, String> {
+    let extension = path.extension()
+      .and_then(|ext| ext.to_str())
+      .map(|ext| ext.to_ascii_lowercase());
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    match extension.as_deref() {
+      Some("hex") | Some("ihx") => {
+        let text = String::from_utf8(bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Program::from_intel_hex(&text)
+      }
+      Some("com") => Program::com(bytes),
+      _ => Program::raw(bytes, 0)
+    }.map_err(|e| format!("{}: {}", path.display(), e))
+  }
+}
+
+fn check_bounds(origin: usize, len: usize) -> Result<(), String> {
+  if origin + len > MEMORY_SIZE {
+    Err(format!("{} bytes at {:04x} do not fit in memory", len, origin))
+  } else {
+    Ok(())
+  }
+}
+
+fn expect_len(len: usize, expected: usize, line_num: usize) -> Result<(), String> {
+  if len != expected {
+    Err(format!("line {}: expected {} data bytes, found {}", line_num, expected, len))
+  } else {
+    Ok(())
+  }
+}
+
+// Decodes a `:LLAAAATT<data>CC` record into its bytes, checksum excluded.
+fn parse_record(line: &str) -> Result<Vec<u8>, String> {
+  let digits = line.strip_prefix(':').ok_or("record does not start with ':'")?;
+  if digits.len() % 2 != 0 || !digits.is_ascii() {
+    return Err("malformed record".to_string());
+  }
+  let bytes = (0..digits.len()).step_by(2)
+    .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+    .collect::<Result<Vec<u8>, _>>()
+    .map_err(|e| format!("invalid hex digit: {}", e))?;
+  if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+    return Err("record length does not match byte count".to_string());
+  }
+  let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+  if sum != 0 {
+    return Err("checksum mismatch".to_string());
+  }
+  Ok(bytes[..bytes.len() - 1].to_vec())
+}
+
+#[cfg(test)]
+mod test {
+  use crate::cpu::loader::{Program, Segment, HaltCondition};
+
+  #[test]
+  fn intel_hex_segments_and_start_address() {
+    let hex = "\
+:0401000031FF00C308
+:02020000760086
+:040000050000010AEC
+:00000001FF
+";
+    let program = Program::from_intel_hex(hex).unwrap();
+    assert_eq!(program.segments, vec![
+      Segment { origin: 0x0100, bytes: vec![0x31, 0xff, 0x00, 0xc3] },
+      Segment { origin: 0x0200, bytes: vec![0x76, 0x00] }
+    ]);
+    assert_eq!(program.entry, 0x010a);
+    assert_eq!(program.halt_condition, HaltCondition::Hlt);
+  }
+
+  #[test]
+  fn intel_hex_bad_checksum() {
+    let hex = ":0401000031FF00C309\n:00000001FF\n";
+    assert_eq!(Program::from_intel_hex(hex), Err("line 1: checksum mismatch".to_string()));
+  }
+}
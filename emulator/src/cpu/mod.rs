@@ -1,6 +1,12 @@
+pub mod loader;
+
 use crate::machines::IO;
 use std::cell::RefCell;
-use std::rc::Rc;
+use loader::{Program, HaltCondition};
+use crate::state::{StateWriter, StateReader};
+
+pub const MEMORY_SIZE: usize = 64000;
+
 #[derive(Debug)]
 enum Register {
   A,
@@ -115,7 +121,8 @@ enum Op {
   Cpi(u8),
   In(u8),
   Di(),
-  Daa()
+  Daa(),
+  Hlt()
 }
 
 impl Op {
@@ -167,7 +174,8 @@ impl Op {
       | Op::Sphl()
       | Op::Ei()
       | Op::Di()
-      | Op::Daa() => 1,
+      | Op::Daa()
+      | Op::Hlt() => 1,
 
       Op::Mvi(_, _)
       | Op::Ani(_)
@@ -207,6 +215,8 @@ impl Op {
       | Op::Cm(_)  => 3,
     }
   }
+  // format! for every op, with or without operands, keeps the table even
+  #[allow(clippy::useless_format)]
   fn print(&self) -> String {
     match self {
       Op::Incr(reg) => format!("INCR {}", reg.to_string()),
@@ -290,6 +300,7 @@ impl Op {
       Op::In(_) => format!("IN"),
       Op::Di() => format!("DI"),
       Op::Daa() => format!("DAA"),
+      Op::Hlt() => format!("HLT"),
       Op::Nop => format!("NOP")
     }
   }
@@ -313,7 +324,7 @@ pub struct State {
   l: u8,
   pub sp: usize,
   pub pc: usize,
-  pub memory: [u8; 64000],
+  pub memory: [u8; MEMORY_SIZE],
   flags: Flags
 }
 
@@ -398,14 +409,22 @@ impl State {
 
 pub struct Cpu {
   pub state: State,
-  program_size: usize,
+  halt_condition: HaltCondition,
+  halted: bool,
   interrupts_enabled: bool,
-  op_history: Vec<String>
+  op_history: Vec<String>,
+  // print every op to stderr as it runs
+  trace: bool,
+  ops: u64
 }
 
 impl Cpu {
-  pub fn new(bytes: Vec<u8>) -> Self {
-    let mut state = State {
+  pub fn new(bytes: Vec<u8>) -> Result<Self, String> {
+    Ok(Cpu::from_program(&Program::raw(bytes, 0)?))
+  }
+
+  pub fn from_program(program: &Program) -> Self {
+    let state = State {
       a: 0,
       b: 0,
       c: 0,
@@ -415,7 +434,7 @@ impl Cpu {
       l: 0,
       sp: 0,
       pc: 0,
-      memory: [0; 64000],
+      memory: [0; MEMORY_SIZE],
       flags: Flags {
         z: 0,
         s: 0,
@@ -424,26 +443,95 @@ impl Cpu {
         ac: 0
       }
     };
-    let program_size = bytes.len();
-    for (i, b) in bytes.into_iter().enumerate() {
-      state.memory[i] = b;
-    }
-    Cpu {
+    let mut cpu = Cpu {
       state,
-      program_size,
+      halt_condition: HaltCondition::Hlt,
+      halted: false,
       interrupts_enabled: false,
-      op_history: Vec::new()
+      op_history: Vec::new(),
+      trace: false,
+      ops: 0
+    };
+    cpu.load(program);
+    cpu
+  }
+
+  // Copies every segment of the program into memory and jumps to its entry point.
+  pub fn load(&mut self, program: &Program) {
+    for segment in &program.segments {
+      let end = segment.origin + segment.bytes.len();
+      self.state.memory[segment.origin..end].copy_from_slice(&segment.bytes);
+    }
+    self.state.pc = program.entry;
+    self.halt_condition = program.halt_condition;
+    self.halted = false;
+  }
+
+  // Like the RESET line: only the program counter, interrupt enable and halt
+  // state change, memory and registers are left alone.
+  pub fn reset(&mut self) {
+    self.state.pc = 0;
+    self.interrupts_enabled = false;
+    self.halted = false;
+  }
+
+  pub fn set_halt_condition(&mut self, halt_condition: HaltCondition) {
+    self.halt_condition = halt_condition;
+  }
+
+  pub fn set_trace(&mut self, trace: bool) {
+    self.trace = trace;
+  }
+
+  // Registers, flags, interrupt state and memory, for save states.
+  pub fn write_state(&self, out: &mut StateWriter) {
+    let state = &self.state;
+    for register in [state.a, state.b, state.c, state.d, state.e, state.h, state.l] {
+      out.u8(register);
+    }
+    out.u16(state.sp as u16);
+    out.u16(state.pc as u16);
+    let flags = &state.flags;
+    for flag in [flags.z, flags.s, flags.p, flags.cy, flags.ac] {
+      out.u8(flag);
+    }
+    out.bool(self.interrupts_enabled);
+    out.bool(self.halted);
+    out.bytes(&state.memory);
+  }
+
+  pub fn read_state(&mut self, input: &mut StateReader) -> Result<(), String> {
+    let state = &mut self.state;
+    for register in [&mut state.a, &mut state.b, &mut state.c, &mut state.d, &mut state.e, &mut state.h, &mut state.l] {
+      *register = input.u8()?;
+    }
+    state.sp = input.u16()? as usize;
+    state.pc = input.u16()? as usize;
+    let flags = &mut state.flags;
+    for flag in [&mut flags.z, &mut flags.s, &mut flags.p, &mut flags.cy, &mut flags.ac] {
+      *flag = input.u8()?;
+    }
+    self.interrupts_enabled = input.bool()?;
+    self.halted = input.bool()?;
+    self.state.memory.copy_from_slice(input.bytes(MEMORY_SIZE)?);
+    Ok(())
+  }
+
+  pub fn is_halted(&self) -> bool {
+    match self.halt_condition {
+      HaltCondition::Hlt => self.halted,
+      HaltCondition::Address(address) => self.halted || self.state.pc == address
     }
   }
 
   fn read_next_op(&self) -> Result<Op, String> {
     let byte = self.state.memory[self.state.pc];
-    let byte2 = if self.state.pc + 1 < 64000 {
+    let byte2 = if self.state.pc + 1 < MEMORY_SIZE {
       self.state.memory[self.state.pc + 1]
     } else {
       0
     };
-    let byte3 = if self.state.pc + 2 < 64000 {
+    let byte3 = if self.state.pc + 2 < MEMORY_SIZE {
       self.state.memory[self.state.pc + 2]
     } else {
       0
@@ -759,6 +847,8 @@ impl Cpu {
       0xf3 => Ok(Op::Di()),
       // DAA
       0x27 => Ok(Op::Daa()),
+      // HLT
+      0x76 => Ok(Op::Hlt()),
       _ => Err(format!("{:04x}", byte))
     }
   }
@@ -771,10 +861,10 @@ impl Cpu {
         4
       }
       Op::Incr(reg) => {
-        let val = self.state.get_register(&reg);
+        let val = self.state.get_register(reg);
         let (answer, overflowed) = val.overflowing_add(1);
         self.set_flags(answer);
-        self.state.set_register(&reg, answer);
+        self.state.set_register(reg, answer);
         self.state.flags.cy = if overflowed {
           1
         } else {
@@ -783,10 +873,10 @@ impl Cpu {
         5
       }
       Op::Decr(reg) => {
-        let val = self.state.get_register(&reg);
+        let val = self.state.get_register(reg);
         let (answer, overflowed) = val.overflowing_sub(1);
         self.set_flags(answer);
-        self.state.set_register(&reg, answer);
+        self.state.set_register(reg, answer);
         self.state.flags.cy = if overflowed {
           1
         } else {
@@ -1089,7 +1179,7 @@ impl Cpu {
           0
         };
 
-        self.state.a = answer as u8;
+        self.state.a = answer;
         7
       }
       Op::Rz() => {
@@ -1133,7 +1223,8 @@ impl Cpu {
               offset += 1;
             }
             println!();
-            std::process::exit(0);
+            // return straight to the caller as if BDOS had handled the call
+            return 17;
         }
         let return_address = ((self.state.pc) as u16).to_le_bytes();
         self.state.memory[self.state.sp - 1] = return_address[1];
@@ -1265,12 +1356,12 @@ impl Cpu {
       Op::Ani(val) => {
         let answer = self.state.a & *val;
         self.set_flags(answer);  
-        self.state.flags.cy = if self.state.a < (answer as u8) {
+        self.state.flags.cy = if self.state.a < answer {
           1
         } else {
           0
         };
-        self.state.a = answer as u8;
+        self.state.a = answer;
         7
       }
       Op::Rpe() => {
@@ -1293,12 +1384,8 @@ impl Cpu {
         10
       }
       Op::Xchg() => {
-        let tmp = self.state.h;
-        self.state.h = self.state.d;
-        self.state.d = tmp;
-        let tmp = self.state.l;
-        self.state.l = self.state.e;
-        self.state.e = tmp;
+        std::mem::swap(&mut self.state.h, &mut self.state.d);
+        std::mem::swap(&mut self.state.l, &mut self.state.e);
         5
       }
       Op::Cpe(val) => {
@@ -1316,12 +1403,12 @@ impl Cpu {
       Op::Xri(val) => {
         let answer = self.state.a ^ *val;
         self.set_flags(answer);  
-        self.state.flags.cy = if self.state.a < (answer as u8) {
+        self.state.flags.cy = if self.state.a < answer {
           1
         } else {
           0
         };
-        self.state.a = answer as u8;
+        self.state.a = answer;
         7
       }
       Op::Rp() => {
@@ -1390,7 +1477,7 @@ impl Cpu {
         (self.state.flags.s << 7) as u16 |
         (self.state.flags.z << 6) as u16 |
         (self.state.flags.p << 2) as u16 |
-        1 << 1 as u16 |
+        1 << 1 |
         self.state.flags.cy as u16;
         self.state.memory[self.state.sp - 2] = flags as u8;
         self.state.memory[self.state.sp - 1] = self.state.a;
@@ -1400,12 +1487,12 @@ impl Cpu {
       Op::Ori(val) => {
         let answer = self.state.a | *val;
         self.set_flags(answer);  
-        self.state.flags.cy = if self.state.a < (answer as u8) {
+        self.state.flags.cy = if self.state.a < answer {
           1
         } else {
           0
         };
-        self.state.a = answer as u8;
+        self.state.a = answer;
         7
       }
       Op::Rm() => {
@@ -1489,13 +1576,15 @@ impl Cpu {
 
         4
       }
+      Op::Hlt() => {
+        self.halted = true;
+        7
+      }
     }
   }
 
   pub fn run(&mut self, io: &RefCell<dyn IO>) {
-    let mut n = 0;
-    while self.state.pc < self.program_size {
-      n += 1;
+    while !self.is_halted() {
       let op_code = self.read_next_op();
       match op_code {
         Ok(op) => {
@@ -1509,14 +1598,21 @@ impl Cpu {
   }
 
   pub fn execute_next_op(&mut self, io: &RefCell<dyn IO>) -> Result<u8, &str> {
+      if self.halted {
+        // a halted 8080 idles until the next interrupt
+        return Ok(4);
+      }
       let op_code = self.read_next_op();
       match op_code {
         Ok(op) => {
+          if self.trace {
+            eprintln!("{}", print_debug_info(&self.state, &op, self.ops));
+            self.ops += 1;
+          }
           let cycles = self.execute_op(op, io);
-          let result = Ok(cycles);
-          result
+          Ok(cycles)
         },
-        Err(op) => {
+        Err(_op) => {
           Err("Error executing op {}")
         }
       }
@@ -1524,6 +1620,7 @@ impl Cpu {
 
   pub fn interrupt(&mut self, interrupt_num: u8) -> bool {
     if self.interrupts_enabled {   
+      self.halted = false;
       let return_address = ((self.state.pc) as u16).to_le_bytes();
       self.state.memory[self.state.sp - 1] = return_address[1];
       self.state.memory[self.state.sp - 2] = return_address[0];
@@ -1548,7 +1645,7 @@ impl Cpu {
       0
     };
 
-    self.state.flags.p = parity(val as u8);
+    self.state.flags.p = parity(val);
   }
 }
 
@@ -1560,6 +1657,7 @@ pub fn parity(b: u8) -> u8 {
   }
 }
 
+// one line per op when tracing
 fn print_debug_info(state: &State, op: &Op, counter: u64) -> String {
   //print!("\x1B[2J\x1B[1;1H");
   let mut log_str = format!("{:04x}: {:11} a:{:02x} b:{:02x} c:{:02x} d:{:02x} e:{:02x} lh:{:02x}{:02x} pc:{:04x} sp:{:04x} spvalue:{:04x} | {} {}",
@@ -1597,6 +1695,8 @@ fn print_debug_info(state: &State, op: &Op, counter: u64) -> String {
 mod test {
   use std::fs;
   use crate::cpu::Cpu;
+  use crate::cpu::loader::Program;
+  use crate::machines::DipSwitches;
   use crate::machines::spaceinvaders::SpaceInvadersIO;
   use crate::machines::Speaker;
   use std::cell::RefCell;
@@ -1614,23 +1714,18 @@ mod test {
   }
   #[test]
   fn cpudiag() {
-    let result = fs::read("resources/cpudiag.bin");
+    let result = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../resources/cpudiag.bin"));
     if let Ok(mut bytes) = result {
-        for _ in 0..256 {
-          bytes.insert(0, 0);
-        }
-        bytes[0] = 0xc3;
-        bytes[1] = 0;
-        bytes[2] = 0x01;
-      
-        bytes[368] = 0x7;
+        // offsets are relative to the 0x100 load address
+        bytes[368 - 0x100] = 0x7;
 
-        bytes[0x59c] = 0xc3;
-        bytes[0x59d] = 0xc2;
-        bytes[0x59e] = 0x05;
-        let space_invaders_io = &RefCell::new(SpaceInvadersIO::new(Box::new(TestSpeaker{})));
-        let mut cpu = Cpu::new(bytes);
+        bytes[0x59c - 0x100] = 0xc3;
+        bytes[0x59d - 0x100] = 0xc2;
+        bytes[0x59e - 0x100] = 0x05;
+        let space_invaders_io = &RefCell::new(SpaceInvadersIO::new(Box::new(TestSpeaker{}), &DipSwitches::default()));
+        let mut cpu = Cpu::from_program(&Program::com(bytes).unwrap());
         cpu.run(space_invaders_io);
+        assert_eq!(cpu.state.pc, 0);
     } else {
         println!("Error reading file {:?}", result);
     }
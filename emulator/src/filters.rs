@@ -0,0 +1,155 @@
+// Software filters run over each RGB frame before it is shown, so they work
+// the same with or without a GPU.
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    // blends in the fading previous frame, like a slow CRT phosphor
+    Phosphor,
+    // doubles the size, rounding off diagonal edges (also known as EPX)
+    Scale2x,
+    // doubles the height with every other line darkened
+    Scanlines
+}
+
+// How much of the previous frame is left after one frame.
+const PHOSPHOR_DECAY: f32 = 0.6;
+const SCANLINE_LEVEL: u32 = 40;
+
+// Runs the filters in the order they were given. Frames are RGB, 3 bytes per
+// pixel, row by row from the top left.
+pub struct FilterChain {
+    filters: Vec<Filter>,
+    // last output of each phosphor filter in the chain
+    afterglow: Vec<Vec<u8>>,
+    output: Vec<u8>,
+    scratch: Vec<u8>
+}
+
+impl FilterChain {
+    pub fn new(filters: &[Filter]) -> Self {
+        FilterChain {
+            filters: filters.to_vec(),
+            afterglow: Vec::new(),
+            output: Vec::new(),
+            scratch: Vec::new()
+        }
+    }
+
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        self.filters.iter().fold((width, height), |(width, height), filter| match filter {
+            Filter::Phosphor => (width, height),
+            Filter::Scale2x => (width * 2, height * 2),
+            Filter::Scanlines => (width, height * 2)
+        })
+    }
+
+    pub fn apply(&mut self, frame: &[u8], width: usize, height: usize) -> &[u8] {
+        self.output.clear();
+        self.output.extend_from_slice(frame);
+        let (mut width, mut height) = (width, height);
+        let mut phosphors = 0;
+        for filter in self.filters.iter() {
+            match filter {
+                Filter::Phosphor => {
+                    if self.afterglow.len() == phosphors {
+                        self.afterglow.push(Vec::new());
+                    }
+                    phosphor(&mut self.output, &mut self.afterglow[phosphors]);
+                    phosphors += 1;
+                    continue;
+                }
+                Filter::Scale2x => {
+                    scale2x(&self.output, width, height, &mut self.scratch);
+                    width *= 2;
+                    height *= 2;
+                }
+                Filter::Scanlines => {
+                    scanlines(&self.output, width, &mut self.scratch);
+                    height *= 2;
+                }
+            }
+            std::mem::swap(&mut self.output, &mut self.scratch);
+        }
+        &self.output
+    }
+}
+
+fn phosphor(frame: &mut [u8], afterglow: &mut Vec<u8>) {
+    if afterglow.len() != frame.len() {
+        afterglow.clear();
+        afterglow.extend_from_slice(frame);
+        return;
+    }
+    for (pixel, glow) in frame.iter_mut().zip(afterglow.iter_mut()) {
+        let faded = (*glow as f32 * PHOSPHOR_DECAY) as u8;
+        *pixel = (*pixel).max(faded);
+        *glow = *pixel;
+    }
+}
+
+fn scale2x(frame: &[u8], width: usize, height: usize, out: &mut Vec<u8>) {
+    out.clear();
+    out.resize(frame.len() * 4, 0);
+    let pixel = |x: usize, y: usize| &frame[(y * width + x) * 3..(y * width + x) * 3 + 3];
+    for y in 0..height {
+        for x in 0..width {
+            let p = pixel(x, y);
+            let a = pixel(x, y.saturating_sub(1));
+            let b = pixel((x + 1).min(width - 1), y);
+            let c = pixel(x.saturating_sub(1), y);
+            let d = pixel(x, (y + 1).min(height - 1));
+            let corners = [
+                if c == a && c != d && a != b { a } else { p },
+                if a == b && a != c && b != d { b } else { p },
+                if d == c && d != b && c != a { c } else { p },
+                if b == d && b != a && d != c { d } else { p }
+            ];
+            for (i, corner) in corners.iter().enumerate() {
+                let (out_x, out_y) = (x * 2 + i % 2, y * 2 + i / 2);
+                let offset = (out_y * width * 2 + out_x) * 3;
+                out[offset..offset + 3].copy_from_slice(corner);
+            }
+        }
+    }
+}
+
+fn scanlines(frame: &[u8], width: usize, out: &mut Vec<u8>) {
+    out.clear();
+    for row in frame.chunks(width * 3) {
+        out.extend_from_slice(row);
+        out.extend(row.iter().map(|c| (*c as u32 * SCANLINE_LEVEL / 100) as u8));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::filters::{Filter, FilterChain};
+
+    const W: [u8; 3] = [255, 255, 255];
+    const K: [u8; 3] = [0, 0, 0];
+
+    #[test]
+    fn scale2x_rounds_diagonals_and_scanlines_double_height() {
+        // a 2x2 diagonal line
+        let frame: Vec<u8> = [W, K, K, W].concat();
+        let mut chain = FilterChain::new(&[Filter::Scale2x, Filter::Scanlines]);
+        assert_eq!(chain.output_size(2, 2), (4, 8));
+        let out = chain.apply(&frame, 2, 2).to_vec();
+        let row = |y: usize| out[y * 12..y * 12 + 12].to_vec();
+        // the staircase is smoothed into a thicker diagonal
+        assert_eq!(row(0), [W, W, K, K].concat());
+        assert_eq!(row(2), [W, K, W, K].concat());
+        // every other line is dimmed
+        assert_eq!(row(1)[0], 102);
+    }
+
+    #[test]
+    fn phosphor_fades() {
+        let mut chain = FilterChain::new(&[Filter::Phosphor]);
+        chain.apply(&W, 1, 1);
+        assert_eq!(chain.apply(&K, 1, 1), &[153, 153, 153]);
+        assert_eq!(chain.apply(&K, 1, 1), &[91, 91, 91]);
+    }
+}
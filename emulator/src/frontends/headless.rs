@@ -0,0 +1,94 @@
+// Runs a machine for a number of frames as fast as it can, with nothing
+// shown or played, e.g. to record a replay to video or check a save state.
+use std::path::PathBuf;
+use crate::machines::{Screen, Speaker, Controller, Button, Player, Machine, Backends, find_machine, create_machine};
+use crate::frontends::{Options, save_state};
+use crate::resources::default_resources;
+use crate::capture::FrameRecorder;
+use crate::mixer::Mixer;
+use crate::config::Config;
+use crate::overlay::Overlay;
+
+pub struct SilentSpeaker;
+
+impl Speaker for SilentSpeaker {
+    fn start_wav_file(&mut self, _: &str) {}
+    fn stop_wav_file(&mut self, _: &str) {}
+    fn play_wav_file(&mut self, _: &str) {}
+}
+
+pub struct NullScreen;
+
+impl Screen for NullScreen {
+    fn clear(&mut self) {}
+    fn draw(&mut self, _: i32, _: i32, _: (u8, u8, u8)) {}
+    fn present(&mut self) {}
+}
+
+// Nobody at the controls, unless a recording is replayed.
+pub struct NoInput;
+
+impl Controller for NoInput {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        Vec::new()
+    }
+}
+
+pub struct HeadlessFrontend {
+    machine: Box<dyn Machine>,
+    video: Option<FrameRecorder>,
+    overlay: Overlay,
+    // written once the last frame has run
+    save_state: Option<PathBuf>
+}
+
+impl HeadlessFrontend {
+    pub fn new(bytes: Vec<u8>, config: &Config, options: &Options, save_state: Option<PathBuf>) -> Result<Self, String> {
+        let info = find_machine(&config.machine)?;
+        // the mixer still runs so the sound can be recorded
+        let mut mixer = Mixer::new();
+        mixer.set_volume(config.volume as f32 / 100.0);
+        let backends = Backends {
+            speaker: Box::new(SilentSpeaker),
+            // the overlay goes on the recorded frames instead
+            screen: Box::new(NullScreen),
+            controller: options.controller(Box::new(NoInput))?,
+            mixer: Some(mixer),
+            resources: default_resources()
+        };
+        let mut machine = create_machine(info.name, bytes, backends, config)?;
+        options.start(machine.as_mut())?;
+        let video = match &options.record_video {
+            Some(path) => {
+                let (width, height) = machine.screen_size();
+                Some(FrameRecorder::create(path, width, height)?)
+            }
+            None => None
+        };
+        Ok(HeadlessFrontend {
+            machine,
+            video,
+            overlay: config.screen_overlay()?,
+            save_state
+        })
+    }
+
+    pub fn run(mut self, frames: u64) -> Result<(), String> {
+        for _ in 0..frames {
+            self.machine.run_next_frame();
+            if let Some(video) = self.video.as_mut() {
+                let mut frame = self.machine.frame_rgb();
+                self.overlay.tint_rgb(&mut frame, self.machine.screen_size().0);
+                video.write_frame(&frame)?;
+            }
+        }
+        if let Some(video) = self.video.take() {
+            video.finish()?;
+        }
+        self.machine.stop_audio_recording()?;
+        if let Some(path) = &self.save_state {
+            save_state(self.machine.as_ref(), path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+// Frontends host any machine from `machines`, each one behind its feature
+// apart from the headless one.
+#[cfg(feature = "sdl")]
+pub mod sdl;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "web")]
+pub mod web;
+pub mod headless;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::machines::{Machine, MachineInfo, Controller};
+use crate::replay::{InputRecorder, InputPlayer};
+
+// Settings from the command line that the native frontends share.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    pub paused: bool,
+    // loaded at the start, and where the save and load state hotkeys go
+    pub state: Option<PathBuf>,
+    pub record_input: Option<PathBuf>,
+    pub replay_input: Option<PathBuf>,
+    pub record_audio: Option<PathBuf>,
+    pub record_video: Option<PathBuf>,
+    pub trace: bool
+}
+
+impl Options {
+    pub fn state_path(&self, info: &MachineInfo) -> PathBuf {
+        self.state.clone().unwrap_or_else(|| PathBuf::from(format!("{}.state", info.name)))
+    }
+
+    // Wraps the frontend's controller to record or replay the input.
+    pub fn controller(&self, controller: Box<dyn Controller>) -> Result<Box<dyn Controller>, String> {
+        let controller: Box<dyn Controller> = match &self.replay_input {
+            Some(path) => Box::new(InputPlayer::open(path, controller)?),
+            None => controller
+        };
+        match &self.record_input {
+            Some(path) => Ok(Box::new(InputRecorder::create(path, controller)?)),
+            None => Ok(controller)
+        }
+    }
+
+    // Applies the options that act on the machine itself.
+    pub fn start(&self, machine: &mut dyn Machine) -> Result<(), String> {
+        machine.set_trace(self.trace);
+        if let Some(path) = &self.state {
+            load_state(machine, path)?;
+        }
+        if let Some(path) = &self.record_audio {
+            machine.start_audio_recording(path)?;
+            println!("Recording audio to {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+pub fn save_state(machine: &dyn Machine, path: &Path) -> Result<(), String> {
+    fs::write(path, machine.save_state()).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn load_state(machine: &mut dyn Machine, path: &Path) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    machine.load_state(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,552 @@
+use sdl2::pixels::{self, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::Texture;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::video::FullscreenType;
+use sdl2::controller::{GameController, Axis, Button as PadButton};
+use std::collections::VecDeque;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::thread;
+use crate::machines::{Screen, Speaker, Controller, ButtonState, Button, Player, Machine, MachineInfo, Backends, find_machine, create_machine};
+use crate::frontends::{Options, save_state, load_state};
+use crate::resources::default_resources;
+use crate::mixer::{Mixer, SAMPLE_RATE, CHANNELS};
+use crate::input::{KeyMap, ButtonKind, Hotkey};
+use crate::config::{Config, Scaling};
+use crate::capture::{CaptureFormat, FrameRecorder};
+use crate::filters::{Filter, FilterChain};
+use crate::overlay::{Overlay, OverlayScreen};
+
+// Draws into an RGB frame at the machine's resolution, runs the filters over
+// it and uploads it to a streaming texture once per frame. SDL scales the
+// texture to the window, which can be resized or made fullscreen.
+pub struct Sdl2Screen {
+    // declared before the canvas so it is dropped before the renderer
+    texture: Texture,
+    canvas: sdl2::render::WindowCanvas,
+    frame: Vec<u8>,
+    width: usize,
+    height: usize,
+    filters: FilterChain,
+    scaling: Scaling,
+    // set by the frontend, applied on the next present
+    fullscreen: Rc<Cell<bool>>
+}
+
+impl Sdl2Screen {
+    pub fn new(sdl_context: &sdl2::Sdl, title: &str, size: (usize, usize), scale: u32, scaling: Scaling, filters: &[Filter], fullscreen: Rc<Cell<bool>>) -> Result<Sdl2Screen, String> {
+        let video = sdl_context.video()?;
+        let (width, height) = (size.0 as u32, size.1 as u32);
+        // keep the whole window on displays too small for the chosen scale
+        let scale = match video.display_usable_bounds(0) {
+            Ok(bounds) => scale.min((bounds.width() / width).max(1)).min((bounds.height() / height).max(1)),
+            Err(_) => scale
+        };
+        let window = video.window(title, width * scale, height * scale)
+            .position_centered()
+            .resizable()
+            .allow_highdpi()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let mut canvas = window.into_canvas().present_vsync().build().map_err(|e| e.to_string())?;
+        // with a logical size SDL letterboxes to keep the aspect ratio, and
+        // works in pixels rather than points on HiDPI displays
+        if scaling != Scaling::Stretch {
+            canvas.set_logical_size(width, height).map_err(|e| e.to_string())?;
+            canvas.set_integer_scale(scaling == Scaling::Integer)?;
+        }
+
+        canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.present();
+
+        let filters = FilterChain::new(filters);
+        let (texture_width, texture_height) = filters.output_size(size.0, size.1);
+        let texture = canvas.texture_creator()
+            .create_texture_streaming(PixelFormatEnum::RGB24, texture_width as u32, texture_height as u32)
+            .map_err(|e| e.to_string())?;
+
+        let mut screen = Sdl2Screen {
+            texture,
+            canvas,
+            frame: vec![0; size.0 * size.1 * 3],
+            width: size.0,
+            height: size.1,
+            filters,
+            scaling,
+            fullscreen
+        };
+        screen.update_fullscreen()?;
+        Ok(screen)
+    }
+
+    fn update_fullscreen(&mut self) -> Result<(), String> {
+        let wanted = if self.fullscreen.get() { FullscreenType::Desktop } else { FullscreenType::Off };
+        let window = self.canvas.window_mut();
+        if window.fullscreen_state() != wanted {
+            window.set_fullscreen(wanted)?;
+        }
+        Ok(())
+    }
+}
+
+impl Screen for Sdl2Screen {
+    fn clear(&mut self) {
+        self.frame.fill(0);
+    }
+
+    fn draw(&mut self, x: i32, y: i32, color: (u8, u8, u8)) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            let offset = (y as usize * self.width + x as usize) * 3;
+            self.frame[offset..offset + 3].copy_from_slice(&[color.0, color.1, color.2]);
+        }
+    }
+
+    fn present(&mut self) {
+        let (width, _) = self.filters.output_size(self.width, self.height);
+        let pixels = self.filters.apply(&self.frame, self.width, self.height);
+        if let Err(e) = self.texture.update(None, pixels, width * 3) {
+            println!("Error updating screen {}", e);
+        }
+        if self.scaling == Scaling::Stretch {
+            if let Ok((width, height)) = self.canvas.output_size() {
+                let _ = self.canvas.set_scale(width as f32 / self.width as f32, height as f32 / self.height as f32);
+            }
+        }
+        self.canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        // the texture is stretched over the logical screen whatever the filters did to its size
+        let screen = Rect::new(0, 0, self.width as u32, self.height as u32);
+        if let Err(e) = self.canvas.copy(&self.texture, None, screen) {
+            println!("Error drawing screen {}", e);
+        }
+        self.canvas.present();
+        if let Err(e) = self.update_fullscreen() {
+            println!("Error switching fullscreen {}", e);
+        }
+    }
+}
+
+// Hands the mixed PCM to SDL's audio thread.
+struct PcmCallback {
+    buffer: Arc<Mutex<VecDeque<i16>>>
+}
+
+impl AudioCallback for PcmCallback {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        for sample in out.iter_mut() {
+            // play silence on an underrun
+            *sample = buffer.pop_front().unwrap_or(0);
+        }
+    }
+}
+
+// Plays the PCM produced by the emulator's mixer.
+pub struct MixerSpeaker {
+    // playback stops when the device is dropped
+    _device: AudioDevice<PcmCallback>,
+    buffer: Arc<Mutex<VecDeque<i16>>>
+}
+
+impl MixerSpeaker {
+    pub fn new(sdl_context: &sdl2::Sdl) -> Result<Self, String> {
+        let audio = sdl_context.audio()?;
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(CHANNELS as u8),
+            samples: Some(1_024)
+        };
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let device = audio.open_playback(None, &spec, |_| PcmCallback { buffer: buffer.clone() })?;
+        device.resume();
+        Ok(MixerSpeaker {
+            _device: device,
+            buffer
+        })
+    }
+}
+
+impl Speaker for MixerSpeaker {
+    fn start_wav_file(&mut self, _: &str) {}
+    fn stop_wav_file(&mut self, _: &str) {}
+    fn play_wav_file(&mut self, _: &str) {}
+
+    fn queue_samples(&mut self, samples: &[i16]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        // drop what's queued if we've fallen far behind rather than let the latency grow
+        if buffer.len() > MAX_QUEUED_SAMPLES {
+            buffer.clear();
+        }
+        buffer.extend(samples);
+    }
+}
+
+// A quarter of a second of stereo audio.
+const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 4 * CHANNELS;
+
+// How far the analog stick has to be pushed before it counts as a press.
+const STICK_DEADZONE: i16 = 12_000;
+
+struct Gamepad {
+    controller: GameController,
+    stick: Option<ButtonKind>,
+    // buttons down, released if the pad is unplugged
+    held: Vec<Button>
+}
+
+// Up to two hot-pluggable game controllers, the first one connected plays as
+// Player1 and the second as Player2.
+pub struct Gamepads {
+    subsystem: sdl2::GameControllerSubsystem,
+    slots: [Option<Gamepad>; 2]
+}
+
+impl Gamepads {
+    pub fn new(sdl_context: &sdl2::Sdl) -> Result<Gamepads, String> {
+        Ok(Gamepads {
+            subsystem: sdl_context.game_controller()?,
+            slots: [None, None]
+        })
+    }
+
+    fn slot_for(&mut self, instance_id: u32) -> Option<(Player, &mut Gamepad)> {
+        self.slots.iter_mut().enumerate().find_map(|(i, slot)| {
+            match slot {
+                Some(gamepad) if gamepad.controller.instance_id() == instance_id => {
+                    Some((if i == 0 { Player::Player1 } else { Player::Player2 }, gamepad))
+                }
+                _ => None
+            }
+        })
+    }
+
+    fn connect(&mut self, joystick_index: u32) {
+        if let Some(slot) = self.slots.iter_mut().find(|slot| slot.is_none()) {
+            match self.subsystem.open(joystick_index) {
+                Ok(controller) => {
+                    println!("Connected controller {}", controller.name());
+                    *slot = Some(Gamepad { controller, stick: None, held: Vec::new() });
+                }
+                Err(e) => println!("Error opening controller {}: {}", joystick_index, e)
+            }
+        }
+    }
+
+    // Lets go of whatever the pad was holding, so the ship doesn't keep moving.
+    fn disconnect(&mut self, instance_id: u32) -> Vec<(Player, Button)> {
+        let mut buttons = Vec::new();
+        if let Some((player, gamepad)) = self.slot_for(instance_id) {
+            buttons.extend(gamepad.held.iter().map(|button| (player, *button)));
+            buttons.extend(gamepad.stick.map(|stick| (player, stick.with_state(ButtonState::Up))));
+        }
+        for slot in self.slots.iter_mut() {
+            if slot.as_ref().is_some_and(|gamepad| gamepad.controller.instance_id() == instance_id) {
+                *slot = None;
+            }
+        }
+        buttons
+    }
+
+    fn button(&mut self, instance_id: u32, button: PadButton, state: ButtonState) -> Vec<(Player, Button)> {
+        let kind = match button {
+            PadButton::DPadLeft => ButtonKind::Left,
+            PadButton::DPadRight => ButtonKind::Right,
+            PadButton::A | PadButton::B | PadButton::X | PadButton::Y => ButtonKind::Shoot,
+            PadButton::Back => ButtonKind::Coin,
+            PadButton::Start => ButtonKind::OnePlayer,
+            _ => return Vec::new()
+        };
+        match self.slot_for(instance_id) {
+            Some((player, gamepad)) => {
+                // the second pad's start button starts a two player game
+                let kind = if player == Player::Player2 && kind == ButtonKind::OnePlayer { ButtonKind::TwoPlayer } else { kind };
+                let release = kind.with_state(ButtonState::Up);
+                gamepad.held.retain(|held| *held != release);
+                if state == ButtonState::Down {
+                    gamepad.held.push(release);
+                }
+                vec![(player, kind.with_state(state))]
+            }
+            None => Vec::new()
+        }
+    }
+
+    fn axis(&mut self, instance_id: u32, axis: Axis, value: i16) -> Vec<(Player, Button)> {
+        if axis != Axis::LeftX {
+            return Vec::new();
+        }
+        let direction = if value < -STICK_DEADZONE {
+            Some(ButtonKind::Left)
+        } else if value > STICK_DEADZONE {
+            Some(ButtonKind::Right)
+        } else {
+            None
+        };
+        let mut buttons = Vec::new();
+        if let Some((player, gamepad)) = self.slot_for(instance_id) {
+            if gamepad.stick != direction {
+                if let Some(released) = gamepad.stick {
+                    buttons.push((player, released.with_state(ButtonState::Up)));
+                }
+                if let Some(pressed) = direction {
+                    buttons.push((player, pressed.with_state(ButtonState::Down)));
+                }
+                gamepad.stick = direction;
+            }
+        }
+        buttons
+    }
+}
+
+// Keyboard input merged with any connected game controllers. Hotkeys and
+// closing the window are queued for the frontend in `hotkeys`.
+pub struct KeyboardController {
+    event_pump: sdl2::EventPump,
+    key_map: KeyMap,
+    gamepads: Gamepads,
+    hotkeys: Rc<RefCell<Vec<Hotkey>>>,
+    // buttons polled while the machine was paused
+    pending: Vec<(Player, Button)>
+}
+
+impl KeyboardController {
+    pub fn new(sdl_context: sdl2::Sdl, key_map: KeyMap, hotkeys: Rc<RefCell<Vec<Hotkey>>>) -> Result<Self, String> {
+        Ok(KeyboardController {
+            gamepads: Gamepads::new(&sdl_context)?,
+            event_pump: sdl_context.event_pump()?,
+            key_map,
+            hotkeys,
+            pending: Vec::new()
+        })
+    }
+
+    // Handles the waiting events, keeping the buttons for the machine.
+    pub fn poll(&mut self) {
+        let key_map = &self.key_map;
+        let gamepads = &mut self.gamepads;
+        let hotkeys = &self.hotkeys;
+        let buttons = self.event_pump.poll_iter().flat_map(|event| {
+            match event {
+                Event::Quit{..} => {
+                    hotkeys.borrow_mut().push(Hotkey::Quit);
+                    Vec::new()
+                }
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+                    if let Some(hotkey) = key_map.lookup_hotkey(&keycode.name()) {
+                        hotkeys.borrow_mut().push(hotkey);
+                        return Vec::new();
+                    }
+                    key_map.lookup(&keycode.name(), ButtonState::Down)
+                }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    key_map.lookup(&keycode.name(), ButtonState::Up)
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    gamepads.connect(which);
+                    Vec::new()
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    gamepads.disconnect(which)
+                }
+                Event::ControllerButtonDown { which, button, .. } => {
+                    gamepads.button(which, button, ButtonState::Down)
+                }
+                Event::ControllerButtonUp { which, button, .. } => {
+                    gamepads.button(which, button, ButtonState::Up)
+                }
+                Event::ControllerAxisMotion { which, axis, value, .. } => {
+                    gamepads.axis(which, axis, value)
+                }
+                _ => { Vec::new() }
+            }
+        });
+        self.pending.extend(buttons);
+    }
+}
+
+impl Controller for KeyboardController {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        self.poll();
+        self.pending.drain(..).collect()
+    }
+}
+
+
+// Runs any machine in an SDL window and handles the frontend's hotkeys.
+pub struct SdlFrontend {
+    machine: Box<dyn Machine>,
+    info: MachineInfo,
+    controller: Rc<RefCell<KeyboardController>>,
+    hotkeys: Rc<RefCell<Vec<Hotkey>>>,
+    paused: bool,
+    state_path: PathBuf,
+    video_format: CaptureFormat,
+    video: Option<FrameRecorder>,
+    // for the recorded frames, the screen has its own
+    overlay: Overlay,
+    fullscreen: Rc<Cell<bool>>
+}
+
+impl SdlFrontend {
+    // Runs the machine named by `config.machine`.
+    pub fn new(bytes: Vec<u8>, config: &Config, options: &Options) -> Result<Self, String> {
+        let info = find_machine(&config.machine)?;
+        let sdl_context = sdl2::init()?;
+        let speaker = MixerSpeaker::new(&sdl_context)?;
+        let hotkeys = Rc::new(RefCell::new(Vec::new()));
+        let key_map = KeyMap::new(&config.bindings, &config.hotkeys);
+        let fullscreen = Rc::new(Cell::new(config.fullscreen));
+        let size = info.screen_size(config.orientation);
+        let screen = Sdl2Screen::new(&sdl_context, info.title, size, config.scale, config.scaling, &config.filters, fullscreen.clone())?;
+        let overlay = config.screen_overlay()?;
+        let controller = Rc::new(RefCell::new(KeyboardController::new(sdl_context, key_map, hotkeys.clone())?));
+        let mut mixer = Mixer::new();
+        mixer.set_volume(config.volume as f32 / 100.0);
+        let backends = Backends {
+            speaker: Box::new(speaker),
+            screen: Box::new(OverlayScreen::new(Box::new(screen), overlay.clone())),
+            controller: options.controller(Box::new(controller.clone()))?,
+            mixer: Some(mixer),
+            resources: default_resources()
+        };
+        let mut machine = create_machine(info.name, bytes, backends, config)?;
+        options.start(machine.as_mut())?;
+        let mut frontend = SdlFrontend {
+            machine,
+            controller,
+            hotkeys,
+            paused: options.paused,
+            state_path: options.state_path(&info),
+            info,
+            video_format: config.video_format,
+            video: None,
+            overlay,
+            fullscreen
+        };
+        if let Some(path) = &options.record_video {
+            frontend.record_video(path)?;
+        }
+        Ok(frontend)
+    }
+
+    fn record_audio(&mut self, path: &Path) -> Result<(), String> {
+        self.machine.start_audio_recording(path)?;
+        println!("Recording audio to {}", path.display());
+        Ok(())
+    }
+
+    fn toggle_audio_recording(&mut self) {
+        let result = if self.machine.is_recording_audio() {
+            self.machine.stop_audio_recording().map(|_| println!("Stopped recording audio"))
+        } else {
+            self.record_audio(&timestamped_path("recording", "wav"))
+        };
+        if let Err(e) = result {
+            println!("Error recording audio {}", e);
+        }
+    }
+
+    fn record_video(&mut self, path: &Path) -> Result<(), String> {
+        let (width, height) = self.machine.screen_size();
+        self.video = Some(FrameRecorder::create(path, width, height)?);
+        println!("Recording video to {}", path.display());
+        Ok(())
+    }
+
+    fn stop_video_recording(&mut self) -> Result<(), String> {
+        match self.video.take() {
+            Some(video) => video.finish(),
+            None => Ok(())
+        }
+    }
+
+    fn toggle_video_recording(&mut self) {
+        let result = if self.video.is_some() {
+            self.stop_video_recording().map(|_| println!("Stopped recording video"))
+        } else {
+            self.record_video(&timestamped_path("recording", self.video_format.extension()))
+        };
+        if let Err(e) = result {
+            println!("Error recording video {}", e);
+        }
+    }
+
+    fn capture_frame(&mut self) {
+        if let Some(video) = self.video.as_mut() {
+            let mut frame = self.machine.frame_rgb();
+            self.overlay.tint_rgb(&mut frame, self.machine.screen_size().0);
+            if let Err(e) = video.write_frame(&frame) {
+                println!("Error recording video {}", e);
+                self.video = None;
+            }
+        }
+    }
+
+    fn save_state(&self) {
+        match save_state(self.machine.as_ref(), &self.state_path) {
+            Ok(()) => println!("Saved state to {}", self.state_path.display()),
+            Err(e) => println!("Error saving state {}", e)
+        }
+    }
+
+    fn load_state(&mut self) {
+        match load_state(self.machine.as_mut(), &self.state_path) {
+            Ok(()) => println!("Loaded state from {}", self.state_path.display()),
+            Err(e) => println!("Error loading state {}", e)
+        }
+    }
+
+    pub fn play(mut self) {
+        let frame_duration = self.info.frame_duration();
+        loop {
+            let start = Instant::now();
+            if self.paused {
+                // still poll for the hotkeys
+                self.controller.borrow_mut().poll();
+            } else {
+                self.machine.run_next_frame();
+                self.capture_frame();
+            }
+            let hotkeys: Vec<Hotkey> = self.hotkeys.borrow_mut().drain(..).collect();
+            for hotkey in hotkeys {
+                match hotkey {
+                    Hotkey::Quit => {
+                        if let Err(e) = self.machine.stop_audio_recording() {
+                            println!("Error recording audio {}", e);
+                        }
+                        if let Err(e) = self.stop_video_recording() {
+                            println!("Error recording video {}", e);
+                        }
+                        return;
+                    }
+                    Hotkey::RecordAudio => self.toggle_audio_recording(),
+                    Hotkey::RecordVideo => self.toggle_video_recording(),
+                    Hotkey::Fullscreen => self.fullscreen.set(!self.fullscreen.get()),
+                    Hotkey::Pause => self.paused = !self.paused,
+                    Hotkey::SaveState => self.save_state(),
+                    Hotkey::LoadState => self.load_state(),
+                    Hotkey::Reset => self.machine.reset()
+                }
+            }
+            let elapsed = start.elapsed();
+            if elapsed < frame_duration {
+                thread::sleep(frame_duration - elapsed);
+            }
+        }
+    }
+}
+
+// e.g. recording-1700000000.wav in the working directory
+fn timestamped_path(prefix: &str, extension: &str) -> PathBuf {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    PathBuf::from(format!("{}-{}.{}", prefix, seconds, extension))
+}
@@ -0,0 +1,425 @@
+// Plays in a terminal: the screen is drawn with braille or half block
+// characters in 24 bit ANSI colour and there is no sound, so it works over
+// SSH on machines without a display. The sound can still be recorded.
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::io::{self, Stdout, Write};
+use std::rc::Rc;
+use std::time::{Instant, Duration};
+use std::thread;
+use crossterm::{cursor, execute, queue, terminal};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags, PushKeyboardEnhancementFlags, PopKeyboardEnhancementFlags};
+use std::path::PathBuf;
+use crate::machines::{Screen, Controller, ButtonState, Button, Player, Machine, MachineInfo, Backends, find_machine, create_machine};
+use crate::frontends::{Options, save_state, load_state};
+use crate::frontends::headless::SilentSpeaker;
+use crate::resources::default_resources;
+use crate::mixer::Mixer;
+use crate::capture::FrameRecorder;
+use crate::input::{KeyMap, Hotkey};
+use crate::config::{Config, TextMode};
+use crate::overlay::{Overlay, OverlayScreen};
+
+// Most terminals only report key presses, so a key counts as held for this
+// many frames after its last press or repeat.
+const HOLD_FRAMES: u32 = 8;
+
+// How long a message stays under the picture, 3 seconds at 60 frames a second.
+const STATUS_FRAMES: u32 = 180;
+
+pub struct TerminalScreen {
+    out: Stdout,
+    mode: TextMode,
+    width: usize,
+    height: usize,
+    pixels: Vec<Option<(u8, u8, u8)>>,
+    text: String,
+    // skips writing frames that haven't changed
+    last_text: String
+}
+
+impl TerminalScreen {
+    pub fn new(mode: TextMode, size: (usize, usize)) -> Self {
+        TerminalScreen {
+            out: io::stdout(),
+            mode,
+            width: size.0,
+            height: size.1,
+            pixels: vec![None; size.0 * size.1],
+            text: String::new(),
+            last_text: String::new()
+        }
+    }
+
+    // lines of text the picture takes up
+    pub fn rows(&self) -> usize {
+        match self.mode {
+            TextMode::Braille => self.height.div_ceil(4),
+            TextMode::Blocks => self.height.div_ceil(2)
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Option<(u8, u8, u8)> {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x]
+        } else {
+            None
+        }
+    }
+
+    // 2x4 pixels to a cell, in the colour of the first lit one
+    fn braille(&mut self) {
+        const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+        let mut color = None;
+        for row in 0..self.height.div_ceil(4) {
+            for column in 0..self.width.div_ceil(2) {
+                let mut dots = 0;
+                let mut cell_color = None;
+                for (dx, column_dots) in DOTS.iter().enumerate() {
+                    for (dy, dot) in column_dots.iter().enumerate() {
+                        if let Some(pixel) = self.pixel(column * 2 + dx, row * 4 + dy) {
+                            dots |= dot;
+                            cell_color = cell_color.or(Some(pixel));
+                        }
+                    }
+                }
+                if let Some((r, g, b)) = cell_color {
+                    if color != cell_color {
+                        let _ = write!(self.text, "\x1b[38;2;{};{};{}m", r, g, b);
+                        color = cell_color;
+                    }
+                    self.text.push(char::from_u32(0x2800 + dots).unwrap());
+                } else {
+                    self.text.push(' ');
+                }
+            }
+            self.text.push_str("\r\n");
+        }
+    }
+
+    // 1x2 pixels to a cell, the upper half in the foreground colour
+    fn blocks(&mut self) {
+        let mut colors = (None, None);
+        for row in 0..self.height.div_ceil(2) {
+            for column in 0..self.width {
+                let cell = (self.pixel(column, row * 2), self.pixel(column, row * 2 + 1));
+                if cell == (None, None) {
+                    if colors.1.is_some() {
+                        self.text.push_str("\x1b[49m");
+                        colors.1 = None;
+                    }
+                    self.text.push(' ');
+                    continue;
+                }
+                let (r, g, b) = cell.0.unwrap_or((0, 0, 0));
+                if colors.0 != Some((r, g, b)) {
+                    let _ = write!(self.text, "\x1b[38;2;{};{};{}m", r, g, b);
+                    colors.0 = Some((r, g, b));
+                }
+                let (r, g, b) = cell.1.unwrap_or((0, 0, 0));
+                if colors.1 != Some((r, g, b)) {
+                    let _ = write!(self.text, "\x1b[48;2;{};{};{}m", r, g, b);
+                    colors.1 = Some((r, g, b));
+                }
+                self.text.push('▀');
+            }
+            self.text.push_str("\x1b[49m\r\n");
+            colors.1 = None;
+        }
+    }
+}
+
+impl Screen for TerminalScreen {
+    fn clear(&mut self) {
+        self.pixels.fill(None);
+    }
+
+    fn draw(&mut self, x: i32, y: i32, color: (u8, u8, u8)) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            self.pixels[y as usize * self.width + x as usize] = Some(color);
+        }
+    }
+
+    fn present(&mut self) {
+        self.text.clear();
+        match self.mode {
+            TextMode::Braille => self.braille(),
+            TextMode::Blocks => self.blocks()
+        }
+        if self.text == self.last_text {
+            return;
+        }
+        let _ = queue!(self.out, cursor::MoveTo(0, 0));
+        let _ = self.out.write_all(self.text.as_bytes());
+        let _ = self.out.write_all(b"\x1b[0m");
+        let _ = self.out.flush();
+        std::mem::swap(&mut self.text, &mut self.last_text);
+    }
+}
+
+pub struct TerminalController {
+    key_map: KeyMap,
+    hotkeys: Rc<RefCell<Vec<Hotkey>>>,
+    // true when the terminal reports key releases
+    releases: bool,
+    // keys held down and the frames left before they are let go
+    held: Vec<(String, u32)>,
+    // buttons polled while the machine was paused
+    pending: Vec<(Player, Button)>
+}
+
+impl TerminalController {
+    pub fn new(key_map: KeyMap, hotkeys: Rc<RefCell<Vec<Hotkey>>>, releases: bool) -> Self {
+        TerminalController {
+            key_map,
+            hotkeys,
+            releases,
+            held: Vec::new(),
+            pending: Vec::new()
+        }
+    }
+
+    // Handles the waiting key presses, keeping the buttons for the machine.
+    pub fn poll(&mut self) {
+        while let Ok(true) = event::poll(Duration::from_millis(0)) {
+            if let Ok(Event::Key(event)) = event::read() {
+                let buttons = self.key_event(event);
+                self.pending.extend(buttons);
+            }
+        }
+    }
+
+    fn key_event(&mut self, event: KeyEvent) -> Vec<(Player, Button)> {
+        let ctrl_c = event.code == KeyCode::Char('c') && event.modifiers.contains(KeyModifiers::CONTROL);
+        if ctrl_c || event.code == KeyCode::Esc {
+            self.hotkeys.borrow_mut().push(Hotkey::Quit);
+            return Vec::new();
+        }
+        let name = match key_name(event.code) {
+            Some(name) => name,
+            None => return Vec::new()
+        };
+        if event.kind == KeyEventKind::Release {
+            self.held.retain(|(key, _)| *key != name);
+            return self.key_map.lookup(&name, ButtonState::Up);
+        }
+        let already_held = self.held.iter().any(|(key, _)| *key == name);
+        self.held.retain(|(key, _)| *key != name);
+        self.held.push((name.clone(), HOLD_FRAMES));
+        if already_held {
+            return Vec::new();
+        }
+        if let Some(hotkey) = self.key_map.lookup_hotkey(&name) {
+            self.hotkeys.borrow_mut().push(hotkey);
+            return Vec::new();
+        }
+        self.key_map.lookup(&name, ButtonState::Down)
+    }
+}
+
+impl Controller for TerminalController {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        self.poll();
+        let mut buttons: Vec<(Player, Button)> = self.pending.drain(..).collect();
+        if !self.releases {
+            for (_, frames) in self.held.iter_mut() {
+                *frames -= 1;
+            }
+            let key_map = &self.key_map;
+            for (key, _) in self.held.iter().filter(|(_, frames)| *frames == 0) {
+                buttons.extend(key_map.lookup(key, ButtonState::Up));
+            }
+            self.held.retain(|(_, frames)| *frames > 0);
+        }
+        buttons
+    }
+}
+
+// The same names SDL uses, so key bindings work in both frontends.
+fn key_name(code: KeyCode) -> Option<String> {
+    let name = match code {
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Char(c) => c.to_uppercase().to_string(),
+        KeyCode::Left => "Left".to_string(),
+        KeyCode::Right => "Right".to_string(),
+        KeyCode::Up => "Up".to_string(),
+        KeyCode::Down => "Down".to_string(),
+        KeyCode::Enter => "Return".to_string(),
+        KeyCode::Tab => "Tab".to_string(),
+        KeyCode::Backspace => "Backspace".to_string(),
+        KeyCode::F(n) => format!("F{}", n),
+        _ => return None
+    };
+    Some(name)
+}
+
+// Puts the terminal back the way it was, even after a panic.
+struct RawMode {
+    enhanced: bool
+}
+
+impl RawMode {
+    fn enter() -> io::Result<RawMode> {
+        terminal::enable_raw_mode()?;
+        let mut out = io::stdout();
+        execute!(out, terminal::EnterAlternateScreen, cursor::Hide, terminal::Clear(terminal::ClearType::All))?;
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(out, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+        Ok(RawMode { enhanced })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        if self.enhanced {
+            let _ = execute!(out, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(out, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+pub struct TuiFrontend {
+    machine: Box<dyn Machine>,
+    info: MachineInfo,
+    controller: Rc<RefCell<TerminalController>>,
+    hotkeys: Rc<RefCell<Vec<Hotkey>>>,
+    paused: bool,
+    state_path: PathBuf,
+    video: Option<FrameRecorder>,
+    // for the recorded frames, the screen has its own
+    overlay: Overlay,
+    // the line under the picture and how many more frames it's shown for
+    status_row: u16,
+    status_frames: u32,
+    // the terminal is restored when this is dropped
+    _raw_mode: RawMode
+}
+
+impl TuiFrontend {
+    // Runs the machine named by `config.machine`.
+    pub fn new(bytes: Vec<u8>, config: &Config, options: &Options) -> Result<Self, String> {
+        let info = find_machine(&config.machine)?;
+        let raw_mode = RawMode::enter().map_err(|e| e.to_string())?;
+        let hotkeys = Rc::new(RefCell::new(Vec::new()));
+        let key_map = KeyMap::new(&config.bindings, &config.hotkeys);
+        let screen = TerminalScreen::new(config.text_mode, info.screen_size(config.orientation));
+        let controller = Rc::new(RefCell::new(TerminalController::new(key_map, hotkeys.clone(), raw_mode.enhanced)));
+        let overlay = config.screen_overlay()?;
+        let status_row = screen.rows() as u16;
+        // there is no sound, but the mixer still runs so it can be recorded
+        let mut mixer = Mixer::new();
+        mixer.set_volume(config.volume as f32 / 100.0);
+        let backends = Backends {
+            speaker: Box::new(SilentSpeaker),
+            screen: Box::new(OverlayScreen::new(Box::new(screen), overlay.clone())),
+            controller: options.controller(Box::new(controller.clone()))?,
+            mixer: Some(mixer),
+            resources: default_resources()
+        };
+        let mut machine = create_machine(info.name, bytes, backends, config)?;
+        options.start(machine.as_mut())?;
+        let video = match &options.record_video {
+            Some(path) => {
+                let (width, height) = machine.screen_size();
+                Some(FrameRecorder::create(path, width, height)?)
+            }
+            None => None
+        };
+        Ok(TuiFrontend {
+            machine,
+            controller,
+            hotkeys,
+            paused: options.paused,
+            state_path: options.state_path(&info),
+            info,
+            video,
+            overlay,
+            status_row,
+            status_frames: 0,
+            _raw_mode: raw_mode
+        })
+    }
+
+    // Esc or ctrl-c quits, finishing the recordings.
+    pub fn play(mut self) -> Result<(), String> {
+        let frame_duration = self.info.frame_duration();
+        loop {
+            let start = Instant::now();
+            if self.paused {
+                self.controller.borrow_mut().poll();
+            } else {
+                self.machine.run_next_frame();
+                self.capture_frame();
+            }
+            let hotkeys: Vec<Hotkey> = self.hotkeys.borrow_mut().drain(..).collect();
+            for hotkey in hotkeys {
+                match hotkey {
+                    Hotkey::Quit => return self.finish(),
+                    Hotkey::Pause => self.paused = !self.paused,
+                    Hotkey::SaveState => {
+                        let message = match save_state(self.machine.as_ref(), &self.state_path) {
+                            Ok(()) => format!("Saved state to {}", self.state_path.display()),
+                            Err(e) => format!("Error saving state {}", e)
+                        };
+                        self.show_status(&message);
+                    }
+                    Hotkey::LoadState => {
+                        let message = match load_state(self.machine.as_mut(), &self.state_path) {
+                            Ok(()) => format!("Loaded state from {}", self.state_path.display()),
+                            Err(e) => format!("Error loading state {}", e)
+                        };
+                        self.show_status(&message);
+                    }
+                    Hotkey::Reset => self.machine.reset(),
+                    _ => {}
+                }
+            }
+            self.age_status();
+            let elapsed = start.elapsed();
+            if elapsed < frame_duration {
+                thread::sleep(frame_duration - elapsed);
+            }
+        }
+    }
+
+    fn capture_frame(&mut self) {
+        if let Some(video) = self.video.as_mut() {
+            let mut frame = self.machine.frame_rgb();
+            self.overlay.tint_rgb(&mut frame, self.machine.screen_size().0);
+            if let Err(e) = video.write_frame(&frame) {
+                self.video = None;
+                self.show_status(&format!("Error recording video {}", e));
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        if let Some(video) = self.video.take() {
+            video.finish()?;
+        }
+        self.machine.stop_audio_recording()
+    }
+
+    // There is nowhere to print while the terminal is in raw mode, so
+    // messages go on the line under the picture for a few seconds.
+    fn show_status(&mut self, message: &str) {
+        let mut out = io::stdout();
+        let _ = queue!(out, cursor::MoveTo(0, self.status_row), terminal::Clear(terminal::ClearType::CurrentLine));
+        let _ = out.write_all(message.as_bytes());
+        let _ = out.flush();
+        self.status_frames = STATUS_FRAMES;
+    }
+
+    fn age_status(&mut self) {
+        if self.status_frames > 0 {
+            self.status_frames -= 1;
+            if self.status_frames == 0 {
+                let _ = execute!(io::stdout(), cursor::MoveTo(0, self.status_row), terminal::Clear(terminal::ClearType::CurrentLine));
+            }
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::cell::RefCell;
+use crate::machines::{Screen, Speaker, Controller, ButtonState, Button, Player, Machine, MachineInfo, Backends, find_machine, create_machine};
+use crate::input::{KeyMap, ButtonKind};
+use crate::config::Config;
+#[cfg(not(feature = "embedded"))]
+use crate::config::SoundSource;
+use crate::overlay::OverlayScreen;
+use crate::resources::Resources;
+#[cfg(feature = "embedded")]
+use crate::resources::EmbeddedResources;
+#[cfg(not(feature = "embedded"))]
+use crate::resources::HttpResources;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{KeyboardEvent, TouchEvent, Element, HtmlElement, CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+use web_sys::{AudioContext, AudioContextOptions, AudioContextState, AudioBuffer, AudioProcessingEvent, ScriptProcessorNode, GainNode};
+use wasm_bindgen::Clamped;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::collections::VecDeque;
+use crate::mixer::{Mixer, SAMPLE_RATE, CHANNELS};
+
+// Where the page's files come from: built in with the `embedded` feature,
+// otherwise fetched from `base` before the machine starts. The ROM is
+// loaded separately as the player can bring their own.
+#[cfg(feature = "embedded")]
+pub async fn load_resources(_base: &str, _info: &MachineInfo, _config: &Config) -> Result<Box<dyn Resources>, String> {
+    Ok(Box::new(EmbeddedResources))
+}
+
+#[cfg(feature = "embedded")]
+pub async fn load_rom(_base: &str, info: &MachineInfo) -> Result<Vec<u8>, String> {
+    EmbeddedResources.load(info.name, info.rom)
+}
+
+#[cfg(not(feature = "embedded"))]
+pub async fn load_rom(base: &str, info: &MachineInfo) -> Result<Vec<u8>, String> {
+    let mut resources = HttpResources::new(base);
+    resources.fetch(info.name, info.rom).await?;
+    resources.load(info.name, info.rom)
+}
+
+#[cfg(not(feature = "embedded"))]
+pub async fn load_resources(base: &str, info: &MachineInfo, config: &Config) -> Result<Box<dyn Resources>, String> {
+    let mut resources = HttpResources::new(base);
+    if config.sound == SoundSource::Synth {
+        return Ok(Box::new(resources));
+    }
+    for file_name in info.samples(&config.sounds) {
+        if let Err(e) = resources.fetch(info.name, &file_name).await {
+            web_sys::console::error_1(&format!("Error loading sound {}", e).into());
+        }
+    }
+    Ok(Box::new(resources))
+}
+
+// The page's audio output, shared by the speaker and whatever the page
+// uses to set the volume. The machine's mixer queues a frame of PCM at a
+// time and a script processor plays it, as the SDL frontend's audio callback
+// does. Browsers only start audio after the player has pressed a key or
+// touched the page, so it's resumed on the first gesture.
+#[derive(Clone)]
+pub struct WebAudio {
+    context: AudioContext,
+    gain: GainNode,
+    queue: Rc<RefCell<VecDeque<i16>>>,
+    // kept alive for as long as the audio plays
+    _processor: Rc<(ScriptProcessorNode, ProcessCallback)>,
+    volume: Rc<Cell<u8>>,
+    muted: Rc<Cell<bool>>
+}
+
+impl WebAudio {
+    fn new(volume: u8) -> Result<Self, JsValue> {
+        let mut options = AudioContextOptions::new();
+        options.sample_rate(SAMPLE_RATE as f32);
+        let context = AudioContext::new_with_context_options(&options)?;
+        let gain = context.create_gain()?;
+        gain.connect_with_audio_node(&context.destination())?;
+        let queue = Rc::new(RefCell::new(VecDeque::new()));
+        let processor = context.create_script_processor_with_buffer_size_and_number_of_input_channels_and_number_of_output_channels(PROCESSOR_FRAMES, 0, CHANNELS as u32)?;
+        let played = queue.clone();
+        let process = Closure::wrap(Box::new(move |event: AudioProcessingEvent| {
+            if let Ok(buffer) = event.output_buffer() {
+                play_queued(&mut played.borrow_mut(), &buffer);
+            }
+        }) as Box<dyn FnMut(_)>);
+        processor.set_onaudioprocess(Some(process.as_ref().unchecked_ref()));
+        processor.connect_with_audio_node(&gain)?;
+        let audio = WebAudio {
+            context,
+            gain,
+            queue,
+            _processor: Rc::new((processor, process)),
+            volume: Rc::new(Cell::new(volume)),
+            muted: Rc::new(Cell::new(false))
+        };
+        audio.update_gain();
+        let window = web_sys::window().unwrap();
+        let resumed_audio = audio.clone();
+        let gesture_listener = Closure::wrap(Box::new(move || {
+            if resumed_audio.context.state() == AudioContextState::Suspended {
+                let _ = resumed_audio.context.resume();
+            }
+        }) as Box<dyn FnMut()>);
+        for event in ["keydown", "pointerdown", "touchstart"] {
+            window.add_event_listener_with_callback(event, gesture_listener.as_ref().unchecked_ref())?;
+        }
+        gesture_listener.forget();
+        Ok(audio)
+    }
+
+    // 0 (silent) to 100, as `volume` in the config.
+    pub fn set_volume(&self, volume: u8) {
+        self.volume.set(volume.min(100));
+        self.update_gain();
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume.get()
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.set(muted);
+        self.update_gain();
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.get()
+    }
+
+    fn update_gain(&self) {
+        let volume = if self.muted.get() { 0.0 } else { self.volume.get() as f32 / 100.0 };
+        self.gain.gain().set_value(volume);
+    }
+}
+
+type ProcessCallback = Closure<dyn FnMut(AudioProcessingEvent)>;
+
+// About 46ms at the mixer's rate.
+const PROCESSOR_FRAMES: u32 = 2048;
+
+// A quarter of a second of stereo audio.
+const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 4 * CHANNELS;
+
+// Splits the interleaved samples into the output's channels, with silence
+// if the machine has fallen behind.
+fn play_queued(queue: &mut VecDeque<i16>, buffer: &AudioBuffer) {
+    let frames = buffer.length() as usize;
+    let mut channels = vec![vec![0.0; frames]; CHANNELS];
+    for i in 0..frames {
+        for channel in channels.iter_mut() {
+            channel[i] = queue.pop_front().map_or(0.0, |sample| sample as f32 / 32_768.0);
+        }
+    }
+    for (number, channel) in channels.iter().enumerate() {
+        let _ = buffer.copy_to_channel(channel, number as i32);
+    }
+}
+
+// Hands the machine's mixed sound to WebAudio.
+pub struct WebSpeaker {
+    audio: WebAudio
+}
+
+impl Speaker for WebSpeaker {
+    fn start_wav_file(&mut self, _: &str) {}
+    fn stop_wav_file(&mut self, _: &str) {}
+    fn play_wav_file(&mut self, _: &str) {}
+
+    fn queue_samples(&mut self, samples: &[i16]) {
+        let mut queue = self.audio.queue.borrow_mut();
+        // drop what's queued if we've fallen far behind, e.g. while the
+        // audio waits for a gesture, rather than let the latency grow
+        if queue.len() > MAX_QUEUED_SAMPLES {
+            queue.clear();
+        }
+        queue.extend(samples);
+    }
+}
+
+// Runs any machine on the page's canvas.
+pub struct WebFrontend {
+    machine: Rc<RefCell<Box<dyn Machine>>>,
+    audio: WebAudio,
+    frame_duration: f64
+}
+
+impl WebFrontend {
+    // Runs the machine named by `config.machine`, with the samples from
+    // `resources`.
+    pub fn new(bytes: Vec<u8>, config: &Config, resources: Box<dyn Resources>) -> Result<Self, String> {
+        let info = find_machine(&config.machine)?;
+        let audio = WebAudio::new(config.volume).map_err(|_| "the browser has no WebAudio".to_string())?;
+        let screen = WebScreen::new(info.screen_size(config.orientation))?;
+        let backends = Backends {
+            speaker: Box::new(WebSpeaker { audio: audio.clone() }),
+            screen: Box::new(OverlayScreen::new(Box::new(screen), config.screen_overlay()?)),
+            controller: Box::new(Controllers(vec![
+                Box::new(KeyboardController::new(KeyMap::new(&config.bindings, &config.hotkeys))),
+                Box::new(TouchController::new(&info).map_err(|_| "the on-screen controls couldn't be added".to_string())?)
+            ])),
+            // volume and mute are up to `audio`
+            mixer: Some(Mixer::new()),
+            resources
+        };
+        let machine = create_machine(info.name, bytes, backends, config)?;
+        Ok(WebFrontend {
+            machine: Rc::new(RefCell::new(machine)),
+            audio,
+            frame_duration: 1000.0 / info.refresh_rate as f64
+        })
+    }
+
+    // For the page's volume and mute controls, which keep working after `play`.
+    pub fn audio(&self) -> WebAudio {
+        self.audio.clone()
+    }
+
+    // The running machine, e.g. to save and load its state between frames.
+    pub fn machine(&self) -> Rc<RefCell<Box<dyn Machine>>> {
+        self.machine.clone()
+    }
+
+    // Runs as many frames as the time since the last animation frame calls
+    // for, so the game keeps its speed whatever the display's refresh rate.
+    pub fn play(self) {
+        let callback: Rc<RefCell<Option<FrameCallback>>> = Rc::new(RefCell::new(None));
+        let next_callback = callback.clone();
+        let mut last_time: Option<f64> = None;
+        let mut behind = 0.0;
+        *callback.borrow_mut() = Some(Closure::wrap(Box::new(move |time: f64| {
+            // after a long pause, e.g. a hidden tab, carry on rather than catch up
+            behind = (behind + time - last_time.unwrap_or(time)).min(MAX_FRAMES_BEHIND * self.frame_duration);
+            last_time = Some(time);
+            while behind >= self.frame_duration {
+                self.machine.borrow_mut().run_next_frame();
+                behind -= self.frame_duration;
+            }
+            request_animation_frame(next_callback.borrow().as_ref().unwrap());
+        }) as Box<dyn FnMut(f64)>));
+        request_animation_frame(callback.borrow().as_ref().unwrap());
+    }
+}
+
+// called with the time in milliseconds
+type FrameCallback = Closure<dyn FnMut(f64)>;
+
+const MAX_FRAMES_BEHIND: f64 = 4.0;
+
+fn request_animation_frame(callback: &FrameCallback) {
+    web_sys::window().unwrap().request_animation_frame(callback.as_ref().unchecked_ref()).unwrap();
+}
+
+pub struct KeyboardController {
+    button_events: Rc<RefCell<Vec<(Player, Button)>>>
+}
+
+impl KeyboardController {
+    fn new(key_map: KeyMap) -> Self {
+        let button_events = Rc::new(RefCell::new(Vec::new()));
+        let key_map = Rc::new(key_map);
+        let window = web_sys::window().unwrap();
+        let button_events_keydown = button_events.clone();
+        let key_map_keydown = key_map.clone();
+        let keydown_listener = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+            if !event.repeat() {
+                let buttons = key_map_keydown.lookup(&event.code(), ButtonState::Down);
+                button_events_keydown.borrow_mut().extend(buttons);
+            }
+        }) as Box<dyn FnMut(_)>);
+        let button_events_keyup = button_events.clone();
+        let key_map_keyup = key_map.clone();
+        let keyup_listener = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+            let buttons = key_map_keyup.lookup(&event.code(), ButtonState::Up);
+            button_events_keyup.borrow_mut().extend(buttons);
+        }) as Box<dyn FnMut(_)>);
+        window.add_event_listener_with_callback("keydown", keydown_listener.as_ref().unchecked_ref()).unwrap();
+        window.add_event_listener_with_callback("keyup", keyup_listener.as_ref().unchecked_ref()).unwrap();
+        keydown_listener.forget();
+        keyup_listener.forget();
+        KeyboardController {
+            button_events
+        }
+    }
+}
+
+impl Controller for KeyboardController {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        let ret_vec = self.button_events.borrow().to_vec();
+        self.button_events.borrow_mut().clear();
+        ret_vec
+    }
+}
+
+// On-screen buttons for the first player, shown from the first touch. Each
+// touch holds down the button it's over, so one finger can slide between
+// left and right while another fires.
+pub struct TouchController {
+    button_events: Rc<RefCell<Vec<(Player, Button)>>>
+}
+
+impl TouchController {
+    fn new(info: &MachineInfo) -> Result<Self, JsValue> {
+        let window = web_sys::window().unwrap();
+        let document = window.document().unwrap();
+        let controls: HtmlElement = document.create_element("div")?.unchecked_into();
+        controls.set_id("touch-controls");
+        controls.style().set_css_text(
+            "position: fixed; left: 0; right: 0; bottom: 0; display: none; justify-content: space-between; \
+             align-items: flex-end; padding: 16px; touch-action: none; user-select: none; -webkit-user-select: none"
+        );
+        // moving on the left, firing on the right and the rest in between
+        let groups: Vec<Element> = (0..3).map(|_| document.create_element("div")).collect::<Result<_, _>>()?;
+        let inputs: Vec<_> = info.inputs.iter()
+            .filter(|input| input.player == Player::Player1 && input.button != ButtonKind::Tilt)
+            .copied()
+            .collect();
+        for (index, input) in inputs.iter().enumerate() {
+            let (group, size) = match input.button {
+                ButtonKind::Left | ButtonKind::Right => (&groups[0], 72),
+                ButtonKind::Shoot => (&groups[2], 88),
+                _ => (&groups[1], 48)
+            };
+            let button: HtmlElement = document.create_element("div")?.unchecked_into();
+            button.set_text_content(Some(input.label));
+            button.set_attribute("data-input", &index.to_string())?;
+            button.style().set_css_text(&format!(
+                "display: inline-flex; align-items: center; justify-content: center; width: {size}px; height: {size}px; \
+                 margin: 6px; border-radius: 50%; background: rgba(255, 255, 255, 0.2); color: white; font: 12px sans-serif"
+            ));
+            group.append_child(&button)?;
+        }
+        for group in groups.iter() {
+            controls.append_child(group)?;
+        }
+        document.body().ok_or("no body for the on-screen controls")?.append_child(&controls)?;
+
+        let button_events = Rc::new(RefCell::new(Vec::new()));
+        let touch_events = button_events.clone();
+        let touched_controls = controls.clone();
+        let mut held: Vec<usize> = Vec::new();
+        let touch_listener = Closure::wrap(Box::new(move |event: TouchEvent| {
+            event.prevent_default();
+            let touches = event.touches();
+            let now_held: Vec<usize> = (0..touches.length())
+                .filter_map(|i| touches.get(i))
+                .filter_map(|touch| document.element_from_point(touch.client_x() as f32, touch.client_y() as f32))
+                .filter_map(|element| element.closest("[data-input]").ok().flatten())
+                .filter_map(|element| element.get_attribute("data-input")?.parse().ok())
+                .collect();
+            let mut events = touch_events.borrow_mut();
+            for index in held.iter().filter(|index| !now_held.contains(index)) {
+                events.push((inputs[*index].player, inputs[*index].button.with_state(ButtonState::Up)));
+                highlight(&touched_controls, *index, false);
+            }
+            for index in now_held.iter().filter(|index| !held.contains(index)) {
+                events.push((inputs[*index].player, inputs[*index].button.with_state(ButtonState::Down)));
+                highlight(&touched_controls, *index, true);
+            }
+            held = now_held;
+        }) as Box<dyn FnMut(_)>);
+        for event in ["touchstart", "touchmove", "touchend", "touchcancel"] {
+            controls.add_event_listener_with_callback(event, touch_listener.as_ref().unchecked_ref())?;
+        }
+        touch_listener.forget();
+
+        let shown_controls = controls.clone();
+        let show_listener = Closure::wrap(Box::new(move || {
+            let _ = shown_controls.style().set_property("display", "flex");
+        }) as Box<dyn FnMut()>);
+        window.add_event_listener_with_callback("touchstart", show_listener.as_ref().unchecked_ref())?;
+        show_listener.forget();
+
+        Ok(TouchController {
+            button_events
+        })
+    }
+}
+
+fn highlight(controls: &HtmlElement, index: usize, held: bool) {
+    let button = controls.query_selector(&format!("[data-input=\"{}\"]", index)).ok().flatten();
+    if let Some(button) = button.and_then(|button| button.dyn_into::<HtmlElement>().ok()) {
+        let background = if held { "rgba(255, 255, 255, 0.5)" } else { "rgba(255, 255, 255, 0.2)" };
+        let _ = button.style().set_property("background", background);
+    }
+}
+
+impl Controller for TouchController {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        std::mem::take(&mut *self.button_events.borrow_mut())
+    }
+}
+
+// The buttons from all of the page's controllers, so the keyboard still
+// works alongside the on-screen buttons.
+struct Controllers(Vec<Box<dyn Controller>>);
+
+impl Controller for Controllers {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        self.0.iter_mut().flat_map(|controller| controller.get_button_states()).collect()
+    }
+}
+
+// Draws into an RGBA buffer that goes to the canvas in one go. The canvas
+// is the size of the machine's screen and scaled up with CSS to fit the
+// window, with the pixels kept sharp.
+pub struct WebScreen {
+    context: CanvasRenderingContext2d,
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>
+}
+
+impl WebScreen {
+    fn new(size: (usize, usize)) -> Result<Self, String> {
+        let (width, height) = (size.0 as u32, size.1 as u32);
+        let window = web_sys::window().ok_or("there is no browser window to draw in")?;
+        let canvas = window.document()
+            .and_then(|document| document.get_element_by_id("canvas"))
+            .and_then(|canvas| canvas.dyn_into::<HtmlCanvasElement>().ok())
+            .ok_or("the page has no <canvas id=\"canvas\"> to draw on")?;
+
+        canvas.set_width(width);
+        canvas.set_height(height);
+        let style = canvas.style();
+        let _ = style.set_property("image-rendering", "pixelated");
+        let _ = style.set_property("display", "block");
+        let _ = style.set_property("margin", "auto");
+        fit_to_window(&canvas, width, height);
+        let resized_canvas = canvas.clone();
+        let resize_listener = Closure::wrap(Box::new(move || {
+            fit_to_window(&resized_canvas, width, height);
+        }) as Box<dyn FnMut()>);
+        window.add_event_listener_with_callback("resize", resize_listener.as_ref().unchecked_ref())
+            .map_err(|_| "the canvas couldn't follow the window size".to_string())?;
+        resize_listener.forget();
+
+        let context = canvas
+            .get_context("2d")
+            .ok()
+            .flatten()
+            .and_then(|context| context.dyn_into::<CanvasRenderingContext2d>().ok())
+            .ok_or("the browser can't draw on the canvas")?;
+
+        Ok(WebScreen {
+            context,
+            width,
+            height,
+            rgba: vec![0; (width * height * 4) as usize]
+        })
+    }
+}
+
+// The largest scale that fits, keeping the aspect ratio.
+fn fit_to_window(canvas: &HtmlCanvasElement, width: u32, height: u32) {
+    let window = web_sys::window().unwrap();
+    let window_width = window.inner_width().ok().and_then(|width| width.as_f64()).unwrap_or(width as f64);
+    let window_height = window.inner_height().ok().and_then(|height| height.as_f64()).unwrap_or(height as f64);
+    let scale = (window_width / width as f64).min(window_height / height as f64).max(1.0);
+    let style = canvas.style();
+    let _ = style.set_property("width", &format!("{}px", (width as f64 * scale).floor()));
+    let _ = style.set_property("height", &format!("{}px", (height as f64 * scale).floor()));
+}
+
+impl Screen for WebScreen {
+    fn clear(&mut self) {
+        for pixel in self.rgba.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&[0, 0, 0, 0xff]);
+        }
+    }
+
+    fn draw(&mut self, x: i32, y: i32, color: (u8, u8, u8)) {
+        if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+            return;
+        }
+        let offset = ((y as u32 * self.width + x as u32) * 4) as usize;
+        self.rgba[offset..offset + 4].copy_from_slice(&[color.0, color.1, color.2, 0xff]);
+    }
+
+    fn present(&mut self) {
+        match ImageData::new_with_u8_clamped_array_and_sh(Clamped(&self.rgba), self.width, self.height) {
+            Ok(image) => {
+                let _ = self.context.put_image_data(&image, 0.0, 0.0);
+            }
+            Err(e) => web_sys::console::error_1(&e)
+        }
+    }
+}
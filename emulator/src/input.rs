@@ -0,0 +1,183 @@
+use serde::{Serialize, Deserialize};
+use crate::machines::{Button, ButtonState, Player};
+
+// A button without its state, used to describe bindings.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ButtonKind {
+    Shoot,
+    Left,
+    Right,
+    Coin,
+    OnePlayer,
+    TwoPlayer,
+    Tilt
+}
+
+impl ButtonKind {
+    pub fn with_state(self, state: ButtonState) -> Button {
+        match self {
+            ButtonKind::Shoot => Button::Shoot(state),
+            ButtonKind::Left => Button::Left(state),
+            ButtonKind::Right => Button::Right(state),
+            ButtonKind::Coin => Button::Coin(state),
+            ButtonKind::OnePlayer => Button::OnePlayer(state),
+            ButtonKind::TwoPlayer => Button::TwoPlayer(state),
+            ButtonKind::Tilt => Button::Tilt(state)
+        }
+    }
+
+    pub fn of(button: Button) -> (ButtonKind, ButtonState) {
+        match button {
+            Button::Shoot(state) => (ButtonKind::Shoot, state),
+            Button::Left(state) => (ButtonKind::Left, state),
+            Button::Right(state) => (ButtonKind::Right, state),
+            Button::Coin(state) => (ButtonKind::Coin, state),
+            Button::OnePlayer(state) => (ButtonKind::OnePlayer, state),
+            Button::TwoPlayer(state) => (ButtonKind::TwoPlayer, state),
+            Button::Tilt(state) => (ButtonKind::Tilt, state)
+        }
+    }
+}
+
+// Actions for the frontend rather than the machine.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Hotkey {
+    Quit,
+    RecordAudio,
+    RecordVideo,
+    Fullscreen,
+    Pause,
+    SaveState,
+    LoadState,
+    Reset
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HotkeyBinding {
+    pub key: String,
+    pub hotkey: Hotkey
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeyBinding {
+    pub key: String,
+    pub player: Player,
+    pub button: ButtonKind
+}
+
+impl KeyBinding {
+    fn new(key: &str, player: Player, button: ButtonKind) -> Self {
+        KeyBinding {
+            key: key.to_string(),
+            player,
+            button
+        }
+    }
+}
+
+pub fn default_key_bindings() -> Vec<KeyBinding> {
+    vec![
+        KeyBinding::new("C", Player::Player1, ButtonKind::Coin),
+        KeyBinding::new("1", Player::Player1, ButtonKind::OnePlayer),
+        KeyBinding::new("2", Player::Player2, ButtonKind::TwoPlayer),
+        KeyBinding::new("Left", Player::Player1, ButtonKind::Left),
+        KeyBinding::new("Right", Player::Player1, ButtonKind::Right),
+        KeyBinding::new("Space", Player::Player1, ButtonKind::Shoot),
+        KeyBinding::new("A", Player::Player2, ButtonKind::Left),
+        KeyBinding::new("D", Player::Player2, ButtonKind::Right),
+        KeyBinding::new("W", Player::Player2, ButtonKind::Shoot),
+        KeyBinding::new("T", Player::Player1, ButtonKind::Tilt)
+    ]
+}
+
+pub fn default_hotkey_bindings() -> Vec<HotkeyBinding> {
+    vec![
+        HotkeyBinding { key: "F9".to_string(), hotkey: Hotkey::RecordAudio },
+        HotkeyBinding { key: "F10".to_string(), hotkey: Hotkey::RecordVideo },
+        HotkeyBinding { key: "F11".to_string(), hotkey: Hotkey::Fullscreen },
+        HotkeyBinding { key: "P".to_string(), hotkey: Hotkey::Pause },
+        HotkeyBinding { key: "F5".to_string(), hotkey: Hotkey::SaveState },
+        HotkeyBinding { key: "F7".to_string(), hotkey: Hotkey::LoadState },
+        HotkeyBinding { key: "F3".to_string(), hotkey: Hotkey::Reset }
+    ]
+}
+
+// Maps host key names to machine buttons and hotkeys. Key names follow SDL's naming
+// ("C", "1", "Left", "Space") and DOM `KeyboardEvent.code` values ("KeyC",
+// "Digit1", "ArrowLeft") are accepted too, so one config works for both
+// frontends.
+pub struct KeyMap {
+    bindings: Vec<(String, Player, ButtonKind)>,
+    hotkeys: Vec<(String, Hotkey)>
+}
+
+impl KeyMap {
+    pub fn new(bindings: &[KeyBinding], hotkeys: &[HotkeyBinding]) -> Self {
+        KeyMap {
+            bindings: bindings.iter()
+                .map(|binding| (normalize_key(&binding.key), binding.player, binding.button))
+                .collect(),
+            hotkeys: hotkeys.iter()
+                .map(|binding| (normalize_key(&binding.key), binding.hotkey))
+                .collect()
+        }
+    }
+
+    pub fn lookup_hotkey(&self, key: &str) -> Option<Hotkey> {
+        let key = normalize_key(key);
+        self.hotkeys.iter()
+            .find(|(bound_key, _)| *bound_key == key)
+            .map(|(_, hotkey)| *hotkey)
+    }
+
+    pub fn lookup(&self, key: &str, state: ButtonState) -> Vec<(Player, Button)> {
+        let key = normalize_key(key);
+        self.bindings.iter()
+            .filter(|(bound_key, _, _)| *bound_key == key)
+            .map(|(_, player, button)| (*player, button.with_state(state)))
+            .collect()
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMap::new(&default_key_bindings(), &default_hotkey_bindings())
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    let key = key.trim();
+    let key = ["Key", "Digit", "Arrow"].iter()
+        .find_map(|prefix| key.strip_prefix(prefix).filter(|rest| !rest.is_empty()))
+        .unwrap_or(key)
+        .to_lowercase();
+    match key.as_str() {
+        "enter" => "return".to_string(),
+        "shiftleft" => "left shift".to_string(),
+        "shiftright" => "right shift".to_string(),
+        "controlleft" => "left ctrl".to_string(),
+        "controlright" => "right ctrl".to_string(),
+        "altleft" => "left alt".to_string(),
+        "altright" => "right alt".to_string(),
+        _ => key
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::input::KeyMap;
+    use crate::machines::{Button, ButtonState, Player};
+
+    #[test]
+    fn sdl_and_dom_key_names_match() {
+        let key_map = KeyMap::default();
+        for key in &["Left", "ArrowLeft"] {
+            match key_map.lookup(key, ButtonState::Down).as_slice() {
+                [(Player::Player1, Button::Left(ButtonState::Down))] => {}
+                other => panic!("unexpected mapping for {}: {:?}", key, other)
+            }
+        }
+        assert_eq!(key_map.lookup("Digit1", ButtonState::Up).len(), 1);
+        assert!(key_map.lookup("KeyX", ButtonState::Up).is_empty());
+    }
+}
@@ -1,2 +1,13 @@
 pub mod cpu;
-pub mod machines;
\ No newline at end of file
+pub mod machines;
+pub mod frontends;
+pub mod input;
+pub mod config;
+pub mod mixer;
+pub mod wav;
+pub mod capture;
+pub mod overlay;
+pub mod filters;
+pub mod state;
+pub mod replay;
+pub mod resources;
\ No newline at end of file
@@ -1,4 +1,16 @@
 pub mod spaceinvaders;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use crate::config::Config;
+use crate::input::ButtonKind;
+use crate::mixer::Mixer;
+use crate::resources::Resources;
+use crate::overlay::{Overlay, Gel};
 
 pub trait IO {
     fn input(&self, port: u8) -> u8;
@@ -9,6 +21,9 @@ pub trait Speaker {
     fn start_wav_file(&mut self, file_name: &str);
     fn stop_wav_file(&mut self, file_name: &str);
     fn play_wav_file(&mut self, file_name: &str);
+    // Interleaved stereo PCM at `mixer::SAMPLE_RATE`, delivered once per
+    // frame when the machine mixes its own sound.
+    fn queue_samples(&mut self, _samples: &[i16]) {}
 }
 
 pub trait Screen {
@@ -18,25 +33,348 @@ pub trait Screen {
 }
 
 pub trait Machine {
+    fn info(&self) -> MachineInfo;
     fn run_next_frame(&mut self);
+    // width and height of what is drawn on the screen
+    fn screen_size(&self) -> (usize, usize);
+    // The screen as it was last drawn, `screen_size` RGB.
+    fn frame_rgb(&self) -> Vec<u8>;
+
+    fn start_audio_recording(&mut self, _path: &Path) -> Result<(), String> {
+        Err(format!("{} can't record audio", self.info().title))
+    }
+    fn stop_audio_recording(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+    fn is_recording_audio(&self) -> bool {
+        false
+    }
+
+    // The whole machine as bytes, see `state`.
+    fn save_state(&self) -> Vec<u8>;
+    // Leaves the machine as it was if the state can't be loaded.
+    fn load_state(&mut self, bytes: &[u8]) -> Result<(), String>;
+
+    // Prints every CPU op to stderr as it runs.
+    fn set_trace(&mut self, _trace: bool) {}
+
+    // Like the cabinet's reset: the program starts over, with any DIP
+    // switches set since.
+    fn reset(&mut self);
+    // For machines that have them, picked up on the next reset as on the
+    // real board. Checked against `MachineInfo::dip_switches` first.
+    fn set_dip_switches(&mut self, _dip_switches: &DipSwitches) {}
+}
+
+// Which way up the monitor is. Arcade boards often scan out their picture
+// sideways and the cabinet turns the monitor to portrait.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    Upright,
+    // the picture as the board sends it, for a monitor that isn't turned
+    Landscape
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cabinet {
+    Upright,
+    // players sit facing each other and the screen turns round for player 2
+    Cocktail
+}
+
+// How the picture gets from the machine to the screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Monitor {
+    // the picture on an upright monitor
+    pub width: usize,
+    pub height: usize,
+    pub orientation: Orientation,
+    // left and right swapped, for cabinets viewed through a mirror
+    pub mirror: bool,
+    pub cabinet: Cabinet
+}
+
+impl Monitor {
+    // An upright monitor in an upright cabinet.
+    pub fn new(info: &MachineInfo) -> Self {
+        Monitor {
+            width: info.width,
+            height: info.height,
+            orientation: Orientation::Upright,
+            mirror: false,
+            cabinet: Cabinet::Upright
+        }
+    }
+
+    // width and height of the picture
+    pub fn size(&self) -> (usize, usize) {
+        match self.orientation {
+            Orientation::Upright => (self.width, self.height),
+            Orientation::Landscape => (self.height, self.width)
+        }
+    }
+
+    // Where a pixel in upright game coordinates lands on the glass of the
+    // monitor, turned round if a cocktail table is showing player 2's turn.
+    fn glass(&self, x: i32, y: i32, flipped: bool) -> (i32, i32) {
+        if flipped && self.cabinet == Cabinet::Cocktail {
+            (self.width as i32 - 1 - x, self.height as i32 - 1 - y)
+        } else {
+            (x, y)
+        }
+    }
+
+    // Where a pixel on the glass ends up in the picture on the host.
+    fn place(&self, x: i32, y: i32) -> (i32, i32) {
+        let height = self.height as i32;
+        let (x, y) = match self.orientation {
+            Orientation::Upright => (x, y),
+            Orientation::Landscape => (height - 1 - y, x)
+        };
+        if self.mirror {
+            (self.size().0 as i32 - 1 - x, y)
+        } else {
+            (x, y)
+        }
+    }
+
+    // The gels are stuck on the glass, so they colour the picture after a
+    // cocktail flip but turn and mirror along with the monitor. Gels are
+    // cut to the glass first, and ones left with nothing are dropped.
+    pub fn place_overlay(&self, overlay: &Overlay) -> Overlay {
+        let gels = overlay.gels.iter().filter_map(|gel| {
+            let right = gel.x.saturating_add(gel.width).min(self.width as u32);
+            let bottom = gel.y.saturating_add(gel.height).min(self.height as u32);
+            if gel.x >= right || gel.y >= bottom {
+                return None;
+            }
+            let (x0, y0) = self.place(gel.x as i32, gel.y as i32);
+            let (x1, y1) = self.place(right as i32 - 1, bottom as i32 - 1);
+            Some(Gel {
+                x: x0.min(x1) as u32,
+                y: y0.min(y1) as u32,
+                width: (x0 - x1).unsigned_abs() + 1,
+                height: (y0 - y1).unsigned_abs() + 1,
+                color: gel.color
+            })
+        }).collect();
+        Overlay { gels, ..overlay.clone() }
+    }
+}
+
+// A setting of a DIP switch, a flag or a number depending on the switch.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DipValue {
+    Flag(bool),
+    Number(u16)
+}
+
+impl fmt::Display for DipValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DipValue::Flag(flag) => write!(f, "{}", flag),
+            DipValue::Number(number) => write!(f, "{}", number)
+        }
+    }
+}
+
+// DIP switch settings by name, as in the `[dip_switches]` table. Switches
+// left out keep their factory setting.
+pub type DipSwitches = BTreeMap<String, DipValue>;
+
+// Sample file names by sound, as in the `[sounds]` table. Sounds left out
+// play their default sample.
+pub type SoundFiles = BTreeMap<String, String>;
+
+// One setting on the machine's DIP switch bank.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DipSwitchInfo {
+    // the key in the `[dip_switches]` table
+    pub name: &'static str,
+    pub label: &'static str,
+    // what it can be set to, the factory setting first
+    pub values: Vec<DipValue>
+}
+
+// A sound the machine plays, for machines that can play samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SoundInfo {
+    // the key in the `[sounds]` table
+    pub name: &'static str,
+    // the file played unless the config names another
+    pub sample: &'static str
 }
 
-#[derive(Clone, Debug)]
+// A button the machine reads, so a frontend can list or draw its controls.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputInfo {
+    pub player: Player,
+    pub button: ButtonKind,
+    pub label: &'static str
+}
+
+// One ROM chip. The chips are loaded back to back from a single file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RomInfo {
+    pub name: &'static str,
+    pub size: usize,
+    pub crc32: u32
+}
+
+// What a frontend needs to know to host a machine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MachineInfo {
+    // picks the machine on the command line, and names its resources directory
+    pub name: &'static str,
+    pub title: &'static str,
+    // the picture on an upright monitor
+    pub width: usize,
+    pub height: usize,
+    // frames per second
+    pub refresh_rate: u32,
+    pub inputs: Vec<InputInfo>,
+    pub sounds: Vec<SoundInfo>,
+    pub dip_switches: Vec<DipSwitchInfo>,
+    // built in overlays, selected by name with `overlay` in the config,
+    // the first is the default
+    pub overlays: Vec<Overlay>,
+    // the name of the file holding all the chips
+    pub rom: &'static str,
+    pub roms: Vec<RomInfo>
+}
+
+impl MachineInfo {
+    // width and height of the picture on the host
+    pub fn screen_size(&self, orientation: Orientation) -> (usize, usize) {
+        match orientation {
+            Orientation::Upright => (self.width, self.height),
+            Orientation::Landscape => (self.height, self.width)
+        }
+    }
+
+    // Checks the ROM file against the chips' sizes and checksums, e.g. to
+    // warn about a bad dump.
+    pub fn check_rom(&self, bytes: &[u8]) -> Result<(), String> {
+        let size: usize = self.roms.iter().map(|rom| rom.size).sum();
+        if bytes.len() != size {
+            return Err(format!("{} needs {} bytes of ROM, got {}", self.title, size, bytes.len()));
+        }
+        let mut offset = 0;
+        for rom in self.roms.iter() {
+            if crc32fast::hash(&bytes[offset..offset + rom.size]) != rom.crc32 {
+                return Err(format!("{} doesn't match the {} ROM", rom.name, self.title));
+            }
+            offset += rom.size;
+        }
+        Ok(())
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_micros(1_000_000 / self.refresh_rate as u64)
+    }
+
+    // Every switch at its factory setting, for a config to start from.
+    pub fn default_dip_switches(&self) -> DipSwitches {
+        self.dip_switches.iter().map(|dip| (dip.name.to_string(), dip.values[0])).collect()
+    }
+
+    pub fn check_dip_switches(&self, dip_switches: &DipSwitches) -> Result<(), String> {
+        for (name, value) in dip_switches.iter() {
+            let dip = self.dip_switches.iter().find(|dip| dip.name == name)
+                .ok_or_else(|| format!("{} has no DIP switch {}", self.title, name))?;
+            if !dip.values.contains(value) {
+                let values: Vec<String> = dip.values.iter().map(|value| value.to_string()).collect();
+                return Err(format!("{} must be one of {}, got {}", name, values.join(", "), value));
+            }
+        }
+        Ok(())
+    }
+
+    // The setting of switch `name`, its factory setting if `dip_switches`
+    // leaves it out.
+    pub fn dip_switch(&self, dip_switches: &DipSwitches, name: &str) -> Option<DipValue> {
+        let dip = self.dip_switches.iter().find(|dip| dip.name == name)?;
+        Some(dip_switches.get(name).copied().unwrap_or(dip.values[0]))
+    }
+
+    pub fn default_sounds(&self) -> SoundFiles {
+        self.sounds.iter().map(|sound| (sound.name.to_string(), sound.sample.to_string())).collect()
+    }
+
+    pub fn check_sounds(&self, sounds: &SoundFiles) -> Result<(), String> {
+        match sounds.keys().find(|name| !self.sounds.iter().any(|sound| sound.name == name.as_str())) {
+            Some(name) => Err(format!("{} has no sound {}", self.title, name)),
+            None => Ok(())
+        }
+    }
+
+    // The file played for each sound, in the order of `sounds`.
+    pub fn samples(&self, sounds: &SoundFiles) -> Vec<String> {
+        self.sounds.iter().map(|sound| match sounds.get(sound.name) {
+            Some(file_name) => file_name.clone(),
+            None => sound.sample.to_string()
+        }).collect()
+    }
+}
+
+// What a frontend hands a machine to run on.
+pub struct Backends {
+    pub speaker: Box<dyn Speaker>,
+    pub screen: Box<dyn Screen>,
+    pub controller: Box<dyn Controller>,
+    // set when the speaker plays the PCM from `queue_samples`, the machine
+    // then mixes its own sound
+    pub mixer: Option<Mixer>,
+    // where the machine's samples come from
+    pub resources: Box<dyn Resources>
+}
+
+// Every machine the emulator can run.
+pub fn machines() -> Vec<MachineInfo> {
+    vec![spaceinvaders::info()]
+}
+
+pub fn find_machine(name: &str) -> Result<MachineInfo, String> {
+    let machines = machines();
+    match machines.iter().find(|info| info.name == name) {
+        Some(info) => Ok(info.clone()),
+        None => {
+            let names: Vec<&str> = machines.iter().map(|info| info.name).collect();
+            Err(format!("unknown machine {}, expected one of {}", name, names.join(", ")))
+        }
+    }
+}
+
+// Builds the machine called `name`, which takes the settings it cares about
+// from `config`.
+pub fn create_machine(name: &str, bytes: Vec<u8>, backends: Backends, config: &Config) -> Result<Box<dyn Machine>, String> {
+    match find_machine(name)?.name {
+        "spaceinvaders" => spaceinvaders::create(bytes, backends, config),
+        name => Err(format!("{} can't be created", name))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Button {
     Shoot(ButtonState),
     Left(ButtonState),
     Right(ButtonState),
     Coin(ButtonState),
-    OnePlayer(ButtonState)
+    OnePlayer(ButtonState),
+    TwoPlayer(ButtonState),
+    Tilt(ButtonState)
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ButtonState {
     Up, 
     Down
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Player {
     Player1,
     Player2
@@ -44,4 +382,11 @@ pub enum Player {
 
 pub trait Controller {
     fn get_button_states(&mut self) -> Vec<(Player, Button)>;
+}
+
+// Lets a frontend keep hold of the controller it hands to the machine.
+impl<C: Controller> Controller for Rc<RefCell<C>> {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        self.borrow_mut().get_button_states()
+    }
 }
\ No newline at end of file
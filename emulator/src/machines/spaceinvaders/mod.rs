@@ -1,48 +1,481 @@
-#[cfg(not(target_family="wasm"))]
-pub mod sdl;
-#[cfg(target_family="wasm")]
-pub mod web;
+pub mod synth;
 use crate::machines::{Button, ButtonState, Player};
-use crate::machines::{Machine, IO, Screen, Controller, Speaker};
+use crate::machines::{Machine, MachineInfo, InputInfo, RomInfo, SoundInfo, DipSwitchInfo, DipValue, DipSwitches, SoundFiles, Monitor, Backends, IO, Screen, Controller, Speaker};
 use std::cell::RefCell;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
 use crate::cpu::Cpu;
+use crate::overlay::{Overlay, Gel, Color};
+use synth::Synth;
+use crate::mixer::{Mixer, SAMPLE_RATE, CHANNELS};
+use crate::wav::WavWriter;
+use crate::input::ButtonKind;
+use crate::config::{Config, SoundSource};
+use crate::state::{StateWriter, StateReader};
 
 
-const GREEN: (u8, u8, u8) = (0, 255, 0);
-const WHITE: (u8, u8, u8) = (255, 255, 255);
-const RED: (u8, u8, u8) = (255, 0, 0);
+pub const SCREEN_WIDTH: usize = 224;
+pub const SCREEN_HEIGHT: usize = 256;
+
+const GREEN: Color = (0, 255, 0);
+const WHITE: Color = (255, 255, 255);
+const RED: Color = (255, 0, 0);
+// Okabe-Ito colours, told apart with any kind of colour blindness
+const ORANGE: Color = (230, 159, 0);
+const SKY_BLUE: Color = (86, 180, 233);
+const AMBER: Color = (255, 176, 0);
+
+// Port 5 bit the game sets while player 2 is up, flipping a cocktail table's screen.
+const FLIP_SCREEN: u8 = 1 << 5;
+
+fn input(player: Player, button: ButtonKind, label: &'static str) -> InputInfo {
+    InputInfo { player, button, label }
+}
+
+fn sound(name: &'static str, sample: &'static str) -> SoundInfo {
+    SoundInfo { name, sample }
+}
+
+pub fn info() -> MachineInfo {
+    MachineInfo {
+        name: "spaceinvaders",
+        title: "Space Invaders",
+        width: SCREEN_WIDTH,
+        height: SCREEN_HEIGHT,
+        refresh_rate: 60,
+        // coin, start and tilt are shared by both players
+        inputs: vec![
+            input(Player::Player1, ButtonKind::Coin, "Coin"),
+            input(Player::Player1, ButtonKind::OnePlayer, "1 Player"),
+            input(Player::Player1, ButtonKind::TwoPlayer, "2 Players"),
+            input(Player::Player1, ButtonKind::Left, "Left"),
+            input(Player::Player1, ButtonKind::Right, "Right"),
+            input(Player::Player1, ButtonKind::Shoot, "Fire"),
+            input(Player::Player2, ButtonKind::Left, "Left"),
+            input(Player::Player2, ButtonKind::Right, "Right"),
+            input(Player::Player2, ButtonKind::Shoot, "Fire"),
+            input(Player::Player1, ButtonKind::Tilt, "Tilt")
+        ],
+        // in the order of `SOUNDS`
+        sounds: vec![
+            sound("ufo", "ufo.wav"),
+            sound("shot", "shoot.wav"),
+            sound("player_dies", "player_dies.wav"),
+            sound("invader_dies", "invader_dies.wav"),
+            sound("extra_life", "extra_life.wav"),
+            sound("fleet1", "bomp.wav"),
+            sound("fleet2", "fleet2.wav"),
+            sound("fleet3", "fleet3.wav"),
+            sound("fleet4", "fleet4.wav"),
+            sound("ufo_hit", "ufo_hit.wav")
+        ],
+        // the bank read through port 2, only sampled by the game at reset
+        dip_switches: vec![
+            DipSwitchInfo {
+                name: "lives",
+                label: "Ships per game",
+                values: (3..=6).map(DipValue::Number).collect()
+            },
+            DipSwitchInfo {
+                name: "bonus_life",
+                label: "Score for the extra ship",
+                values: vec![DipValue::Number(1500), DipValue::Number(1000)]
+            },
+            DipSwitchInfo {
+                name: "coin_info",
+                label: "Coin info on the attract screen",
+                values: vec![DipValue::Flag(true), DipValue::Flag(false)]
+            }
+        ],
+        overlays: overlay_presets(),
+        rom: "invaders",
+        // Midway's board, h at 0x0000 up to e at 0x1800
+        roms: vec![
+            RomInfo { name: "invaders.h", size: 0x800, crc32: 0x734f5ad8 },
+            RomInfo { name: "invaders.g", size: 0x800, crc32: 0x6bfaca4a },
+            RomInfo { name: "invaders.f", size: 0x800, crc32: 0x0ccead96 },
+            RomInfo { name: "invaders.e", size: 0x800, crc32: 0x14e538b0 }
+        ]
+    }
+}
+
+// Sets the machine up from the config: monitor, DIP switches and the sound.
+// The synthesizer is mixed here and handed to the speaker as PCM whatever the
+// frontend plays; samples are only mixed here if the frontend plays PCM.
+pub fn create(bytes: Vec<u8>, backends: Backends, config: &Config) -> Result<Box<dyn Machine>, String> {
+    let mut machine = SpaceInvaders::new(bytes, backends.speaker, backends.screen, backends.controller, config.dip_switches.clone())?;
+    machine.set_monitor(config.monitor(&info()));
+    let sounds = SoundMap::new(&config.sounds);
+    let mixer = match backends.mixer {
+        None if config.sound == SoundSource::Synth => Some(Mixer::new()),
+        mixer => mixer
+    };
+    if let Some(mut mixer) = mixer {
+        if config.sound == SoundSource::Samples {
+            for file_name in sounds.files() {
+                let result = backends.resources.load(info().name, file_name)
+                    .and_then(|bytes| mixer.load_sample(file_name, &bytes));
+                if let Err(e) = result {
+                    println!("Error loading sound {}", e);
+                }
+            }
+        }
+        machine.enable_mixer(mixer);
+        if config.sound == SoundSource::Synth {
+            machine.enable_synth();
+        }
+    }
+    machine.set_sounds(sounds);
+    Ok(Box::new(machine))
+}
+
+fn band(y: u32, height: u32, color: Color) -> Gel {
+    Gel { x: 0, y, width: SCREEN_WIDTH as u32, height, color }
+}
+
+// Built in overlays, the classic one first as the default.
+fn overlay_presets() -> Vec<Overlay> {
+    vec![
+        // red over the ufo, green over the shields, player and lives
+        Overlay {
+            name: "classic".to_string(),
+            color: WHITE,
+            gels: vec![band(33, 16, RED), band(180, 76, GREEN)]
+        },
+        Overlay {
+            name: "monochrome".to_string(),
+            color: WHITE,
+            gels: Vec::new()
+        },
+        // Midway's upright: the bottom strip only covers the spare ships,
+        // leaving the lives count and credits white
+        Overlay {
+            name: "upright".to_string(),
+            color: WHITE,
+            gels: vec![
+                band(33, 16, RED),
+                band(183, 56, GREEN),
+                Gel { x: 25, y: 239, width: 111, height: 17, color: GREEN }
+            ]
+        },
+        Overlay {
+            name: "colorblind".to_string(),
+            color: WHITE,
+            gels: vec![band(33, 16, ORANGE), band(180, 76, SKY_BLUE)]
+        },
+        Overlay {
+            name: "amber".to_string(),
+            color: AMBER,
+            gels: Vec::new()
+        }
+    ]
+}
+
+// Port 2 bits 0-1 are the number of lives above 3, bit 3 selects the
+// bonus at 1000 and bit 7 hides the coin info.
+fn port2_bits(dip_switches: &DipSwitches) -> u8 {
+    let info = info();
+    let mut bits = 0;
+    if let Some(DipValue::Number(lives)) = info.dip_switch(dip_switches, "lives") {
+        bits |= (lives.clamp(3, 6) - 3) as u8 & 0b11;
+    }
+    if info.dip_switch(dip_switches, "bonus_life") == Some(DipValue::Number(1000)) {
+        bits |= 1 << 3;
+    }
+    if info.dip_switch(dip_switches, "coin_info") == Some(DipValue::Flag(false)) {
+        bits |= 1 << 7;
+    }
+    bits
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sound {
+    Ufo,
+    Shot,
+    PlayerDies,
+    InvaderDies,
+    ExtraLife,
+    Fleet1,
+    Fleet2,
+    Fleet3,
+    Fleet4,
+    UfoHit
+}
+
+pub const SOUNDS: [Sound; 10] = [
+    Sound::Ufo,
+    Sound::Shot,
+    Sound::PlayerDies,
+    Sound::InvaderDies,
+    Sound::ExtraLife,
+    Sound::Fleet1,
+    Sound::Fleet2,
+    Sound::Fleet3,
+    Sound::Fleet4,
+    Sound::UfoHit
+];
+
+// Sound latch bits on output ports 3 and 5.
+const PORT3_SOUNDS: [(u8, Sound); 5] = [
+    (0, Sound::Ufo),
+    (1, Sound::Shot),
+    (2, Sound::PlayerDies),
+    (3, Sound::InvaderDies),
+    (4, Sound::ExtraLife)
+];
+const PORT5_SOUNDS: [(u8, Sound); 5] = [
+    (0, Sound::Fleet1),
+    (1, Sound::Fleet2),
+    (2, Sound::Fleet3),
+    (3, Sound::Fleet4),
+    (4, Sound::UfoHit)
+];
+// Port 3 bit 5 switches the sound amplifier on and off.
+const AMP_ENABLE: u8 = 1 << 5;
+
+// Sample file played for each sound, from the `[sounds]` table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoundMap {
+    // in the order of `SOUNDS`
+    files: Vec<String>
+}
+
+impl Default for SoundMap {
+    fn default() -> Self {
+        SoundMap::new(&SoundFiles::new())
+    }
+}
+
+impl SoundMap {
+    pub fn new(sounds: &SoundFiles) -> Self {
+        SoundMap { files: info().samples(sounds) }
+    }
+
+    pub fn files(&self) -> Vec<&str> {
+        self.files.iter().map(|file_name| file_name.as_str()).collect()
+    }
+
+    pub fn sample(&self, sound: Sound) -> &str {
+        let index = SOUNDS.iter().position(|other| *other == sound).unwrap();
+        &self.files[index]
+    }
+}
 
 pub struct SpaceInvadersIO {
     // read ports
     pub port1: u8,
     pub port2: u8,
+    dip_switches: u8,
     
     shift_register: u16,
     shift_amount: u8,
     prev_port3_val: u8,
     prev_port5_val: u8,
-    speaker: Box<dyn Speaker>
+    speaker: Box<dyn Speaker>,
+    sounds: SoundMap,
+    // when set, sounds are mixed here and the speaker gets PCM through `queue_samples`
+    mixer: Option<Mixer>,
+    synth: Option<Synth>,
+    synth_buffer: Vec<i16>,
+    pcm_buffer: Vec<i16>,
+    recorder: Option<WavWriter<BufWriter<File>>>,
+    // cycles into the current frame, kept up to date by the machine so
+    // latch writes can be placed exactly
+    pub frame_cycle: u64
 }
 
 impl SpaceInvadersIO {
-    pub fn new(speaker: Box<dyn Speaker>) -> Self {
+    pub fn new(speaker: Box<dyn Speaker>, dip_switches: &DipSwitches) -> Self {
         Self {
             shift_register: 0,
             shift_amount: 0,
             port1: 0b0000_1000,
             port2: 0b0000_0000,
+            dip_switches: port2_bits(dip_switches),
             prev_port3_val: 0,
             prev_port5_val: 0,
-            speaker: speaker
+            speaker,
+            sounds: SoundMap::default(),
+            mixer: None,
+            synth: None,
+            synth_buffer: Vec::new(),
+            pcm_buffer: Vec::new(),
+            recorder: None,
+            frame_cycle: 0
+        }
+    }
+
+    pub fn set_sounds(&mut self, sounds: SoundMap) {
+        self.sounds = sounds;
+    }
+
+    // Mixes the sounds in the emulator instead of triggering the speaker's
+    // samples, `mixer` needs the samples from the sound map loaded.
+    pub fn enable_mixer(&mut self, mixer: Mixer) {
+        self.mixer = Some(mixer);
+    }
+
+    // Replaces the samples with the synthesizer.
+    pub fn enable_synth(&mut self) {
+        if self.mixer.is_none() {
+            self.mixer = Some(Mixer::new());
+        }
+        self.synth = Some(Synth::new(SAMPLE_RATE));
+    }
+
+    pub fn end_frame(&mut self, frame_cycles: u64) {
+        if let Some(mixer) = self.mixer.as_mut() {
+            if let Some(synth) = self.synth.as_mut() {
+                self.synth_buffer.clear();
+                synth.end_frame(frame_cycles, &mut self.synth_buffer);
+                mixer.stream(&self.synth_buffer);
+            }
+            self.pcm_buffer.clear();
+            mixer.mix_frame(frame_cycles, &mut self.pcm_buffer);
+            self.speaker.queue_samples(&self.pcm_buffer);
+            if let Some(recorder) = self.recorder.as_mut() {
+                if let Err(e) = recorder.write_samples(&self.pcm_buffer) {
+                    println!("Error recording audio {}", e);
+                    self.recorder = None;
+                }
+            }
+        }
+        self.frame_cycle = 0;
+    }
+
+    // Writes the mixer's output to a WAV file from the next frame on. Time
+    // in the recording is emulated time, so pausing or running faster than
+    // real time doesn't throw it off.
+    pub fn start_recording(&mut self, path: &Path) -> Result<(), String> {
+        if self.mixer.is_none() {
+            return Err("audio can only be recorded when the emulator mixes the sound".to_string());
+        }
+        let recorder = WavWriter::create(path, SAMPLE_RATE, CHANNELS as u16)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        match self.recorder.take() {
+            Some(mut recorder) => recorder.finish().map_err(|e| e.to_string()),
+            None => Ok(())
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn flip_screen(&self) -> bool {
+        self.prev_port5_val & FLIP_SCREEN != 0
+    }
+
+    fn start_sound(&mut self, sound: Sound) {
+        let sample = self.sounds.sample(sound);
+        let looping = sound == Sound::Ufo;
+        match self.mixer.as_mut() {
+            Some(mixer) => mixer.play(sample, self.frame_cycle, looping, 1.0),
+            None if looping => self.speaker.start_wav_file(sample),
+            None => self.speaker.play_wav_file(sample)
+        }
+    }
+
+    fn stop_sound(&mut self, sound: Sound) {
+        let sample = self.sounds.sample(sound);
+        match self.mixer.as_mut() {
+            Some(mixer) => mixer.stop(sample, self.frame_cycle),
+            None => self.speaker.stop_wav_file(sample)
         }
     }
+
+    // Starts the sounds whose latch bit went from 0 to 1, the ufo sound loops
+    // for as long as its bit stays set.
+    fn trigger_sounds(&mut self, sounds: &[(u8, Sound)], prev_val: u8, val: u8) {
+        for (bit, sound) in sounds {
+            let mask = 1 << bit;
+            if val & mask != 0 && prev_val & mask == 0 {
+                self.start_sound(*sound);
+            } else if val & mask == 0 && prev_val & mask != 0 && *sound == Sound::Ufo {
+                self.stop_sound(*sound);
+            }
+        }
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        out.u8(self.port1);
+        out.u8(self.port2);
+        out.u8(self.dip_switches);
+        out.u16(self.shift_register);
+        out.u8(self.shift_amount);
+        out.u8(self.prev_port3_val);
+        out.u8(self.prev_port5_val);
+    }
+
+    // Sounds aren't part of the state: what was playing stops and only the
+    // ufo loop picks up again if its latch is set.
+    pub fn read_state(&mut self, input: &mut StateReader) -> Result<(), String> {
+        self.port1 = input.u8()?;
+        self.port2 = input.u8()?;
+        self.dip_switches = input.u8()?;
+        self.shift_register = input.u16()?;
+        self.shift_amount = input.u8()?;
+        let port3 = input.u8()?;
+        let port5 = input.u8()?;
+        self.silence();
+        match self.synth.as_mut() {
+            Some(synth) => {
+                synth.restore(port3, port5);
+                self.prev_port3_val = port3;
+            }
+            None => {
+                self.prev_port3_val = port3 & !0x1;
+                self.output(3, port3);
+            }
+        }
+        self.prev_port5_val = port5;
+        Ok(())
+    }
+
+    // Stops every sound, including one-shots that are still playing.
+    fn stop_all_sounds(&mut self) {
+        match self.mixer.as_mut() {
+            Some(mixer) => mixer.stop_all(self.frame_cycle),
+            None => {
+                for sound in SOUNDS.iter() {
+                    self.speaker.stop_wav_file(self.sounds.sample(*sound));
+                }
+            }
+        }
+    }
+
+    // Back to the board at power on: nothing playing and the latches clear,
+    // so the next write to them triggers its sounds.
+    fn silence(&mut self) {
+        self.stop_all_sounds();
+        if let Some(synth) = self.synth.as_mut() {
+            synth.restore(0, 0);
+        }
+        self.prev_port3_val = 0;
+        self.prev_port5_val = 0;
+    }
+
+    pub fn reset(&mut self, dip_switches: &DipSwitches) {
+        self.silence();
+        self.shift_register = 0;
+        self.shift_amount = 0;
+        self.port1 = 0b0000_1000;
+        self.port2 = 0b0000_0000;
+        self.dip_switches = port2_bits(dip_switches);
+    }
 }
 
 impl IO for SpaceInvadersIO {
     fn input(&self, port: u8) -> u8 {
         match port {
             1 => self.port1,
-            2 => self.port2,
+            2 => self.port2 | self.dip_switches,
             3 => (self.shift_register >> (8 - self.shift_amount)) as u8,
             _ => panic!("unhandled input port {}", port)
         }
@@ -56,30 +489,35 @@ impl IO for SpaceInvadersIO {
                 let [_, val2] = u16::to_le_bytes(self.shift_register);
                 self.shift_register = u16::from_le_bytes([val2, val]);
             },
-            3 => {
-                if val != self.prev_port3_val {
-                    if val & 0x1 == 1 && self.prev_port3_val & 0x1 == 0 {
-                        self.speaker.start_wav_file("ufo.wav");
-                    } else if val & 0x1 == 0 && self.prev_port3_val & 0x1 == 1 {
-                        self.speaker.stop_wav_file("ufo.wav");
-                    }
-                    if val & 0x2 == 2 && self.prev_port3_val & 0x2 == 0 {
-                        self.speaker.play_wav_file("shoot.wav");
-                    }
-                    if val & 0x4 == 4 && self.prev_port3_val & 0x4 == 0 {
-                        self.speaker.play_wav_file("player_dies.wav");
-                    }
-                    if val & 0x8 == 8 && self.prev_port3_val & 0x8 == 0 {
-                        self.speaker.play_wav_file("invader_dies.wav");
-                    }
+            3 | 5 if self.synth.is_some() => {
+                self.synth.as_mut().unwrap().write(port, val, self.frame_cycle);
+                if port == 3 {
                     self.prev_port3_val = val;
+                } else {
+                    self.prev_port5_val = val;
                 }
             },
-            5 => {
-                if val & 0x1 == 1 && self.prev_port5_val & 0x1 == 0 {
-                    self.speaker.play_wav_file("bomp.wav")
+            3 => {
+                let prev_val = self.prev_port3_val;
+                self.prev_port3_val = val;
+                let amp_enabled = val & AMP_ENABLE != 0;
+                if prev_val & AMP_ENABLE != 0 && !amp_enabled {
+                    // muting cuts off what is already playing too
+                    self.stop_all_sounds();
                 }
+                if !amp_enabled {
+                    return;
+                }
+                // turning the amplifier on with the ufo bit already set starts the loop
+                let prev_val = if prev_val & AMP_ENABLE == 0 { prev_val & !0x1 } else { prev_val };
+                self.trigger_sounds(&PORT3_SOUNDS, prev_val, val);
+            },
+            5 => {
+                let prev_val = self.prev_port5_val;
                 self.prev_port5_val = val;
+                if self.prev_port3_val & AMP_ENABLE != 0 {
+                    self.trigger_sounds(&PORT5_SOUNDS, prev_val, val);
+                }
             },
             6 => {},
             _ => panic!("cannot write to port {}", port)
@@ -92,87 +530,346 @@ pub struct SpaceInvaders {
     io: RefCell<SpaceInvadersIO>,
     cpu: Cpu,
     screen: Box<dyn Screen>,
-    controller: Box<dyn Controller>
+    controller: Box<dyn Controller>,
+    monitor: Monitor,
+    dip_switches: DipSwitches
 }
 
-impl Machine for SpaceInvaders {
-    fn run_next_frame(&mut self) {
-        let mut current_cycles = 0;
-        while current_cycles < 33_000 / 2 {
-            if let Ok(cycles) = self.cpu.execute_next_op(&self.io) {
-                current_cycles += cycles as u64;
-            } 
-        }
+impl SpaceInvaders {
+    // The frontend hands over its backends; the monitor starts out as the
+    // upright cabinet's.
+    pub fn new(bytes: Vec<u8>, speaker: Box<dyn Speaker>, screen: Box<dyn Screen>, controller: Box<dyn Controller>, dip_switches: DipSwitches) -> Result<Self, String> {
+        let cpu = Cpu::new(bytes).map_err(|e| format!("ROM: {}", e))?;
+        Ok(SpaceInvaders {
+            io: RefCell::new(SpaceInvadersIO::new(speaker, &dip_switches)),
+            cpu,
+            screen,
+            controller,
+            monitor: Monitor::new(&info()),
+            dip_switches
+        })
+    }
 
-        self.cpu.interrupt(1);
+    pub fn set_monitor(&mut self, monitor: Monitor) {
+        self.monitor = monitor;
+    }
+
+    pub fn enable_mixer(&mut self, mixer: Mixer) {
+        self.io.borrow_mut().enable_mixer(mixer);
+    }
 
-        current_cycles = 0;
-        while current_cycles < 33_000 {
+    pub fn enable_synth(&mut self) {
+        self.io.borrow_mut().enable_synth();
+    }
+
+    pub fn set_sounds(&mut self, sounds: SoundMap) {
+        self.io.borrow_mut().set_sounds(sounds);
+    }
+
+    // Runs the cpu until `frame_cycles` reaches `target`, keeping the IO
+    // clock in step for the sound timing.
+    fn run_until(&mut self, mut frame_cycles: u64, target: u64) -> u64 {
+        while frame_cycles < target {
+            self.io.borrow_mut().frame_cycle = frame_cycles;
             if let Ok(cycles) = self.cpu.execute_next_op(&self.io) {
-                current_cycles += cycles as u64;
+                frame_cycles += cycles as u64;
             }
         }
+        frame_cycles
+    }
+}
+
+impl Machine for SpaceInvaders {
+    fn info(&self) -> MachineInfo {
+        info()
+    }
+
+    fn reset(&mut self) {
+        self.cpu.reset();
+        self.io.borrow_mut().reset(&self.dip_switches);
+    }
+
+    fn set_dip_switches(&mut self, dip_switches: &DipSwitches) {
+        self.dip_switches = dip_switches.clone();
+    }
+
+    fn screen_size(&self) -> (usize, usize) {
+        self.monitor.size()
+    }
+
+    // in black and white, the frontend puts the overlay on
+    fn frame_rgb(&self) -> Vec<u8> {
+        let (width, _) = self.screen_size();
+        let mut rgb = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3];
+        let flipped = self.io.borrow().flip_screen();
+        draw_screen(&self.cpu.state.memory, &self.monitor, flipped, |x, y, color| {
+            let offset = (y as usize * width + x as usize) * 3;
+            rgb[offset..offset + 3].copy_from_slice(&[color.0, color.1, color.2]);
+        });
+        rgb
+    }
+
+    fn start_audio_recording(&mut self, path: &Path) -> Result<(), String> {
+        self.io.borrow_mut().start_recording(path)
+    }
+
+    fn stop_audio_recording(&mut self) -> Result<(), String> {
+        self.io.borrow_mut().stop_recording()
+    }
+
+    fn is_recording_audio(&self) -> bool {
+        self.io.borrow().is_recording()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut out = StateWriter::new(info().name);
+        self.cpu.write_state(&mut out);
+        self.io.borrow().write_state(&mut out);
+        out.finish()
+    }
+
+    fn load_state(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+        let result = StateReader::new(bytes, info().name).and_then(|mut input| {
+            self.cpu.read_state(&mut input)?;
+            self.io.borrow_mut().read_state(&mut input)
+        });
+        if result.is_err() {
+            let mut input = StateReader::new(&backup, info().name)?;
+            self.cpu.read_state(&mut input)?;
+            self.io.borrow_mut().read_state(&mut input)?;
+        }
+        result
+    }
+
+    fn set_trace(&mut self, trace: bool) {
+        self.cpu.set_trace(trace);
+    }
+
+    fn run_next_frame(&mut self) {
+        let frame_cycles = self.run_until(0, 33_000 / 2);
+
+        self.cpu.interrupt(1);
+
+        let frame_cycles = self.run_until(frame_cycles, 33_000 / 2 + 33_000);
 
         self.cpu.interrupt(2);
+        self.io.borrow_mut().end_frame(frame_cycles);
 
-        for button in self.controller.get_button_states() {
-            match button {
-                (Player::Player1, Button::Coin(ButtonState::Down)) => {
-                    self.io.borrow_mut().port1 |= 1 << 0x00;
-                }
-                (Player::Player1, Button::OnePlayer(ButtonState::Down)) => {
-                    self.io.borrow_mut().port1 |= 1 << 0x02;
-                }
-                (Player::Player1, Button::Left(ButtonState::Down)) => {
-                    self.io.borrow_mut().port1 |= 1 << 0x05;
-                }
-                (Player::Player1, Button::Right(ButtonState::Down)) => {
-                    self.io.borrow_mut().port1 |= 1 << 0x06;
-                }
-                (Player::Player1, Button::Shoot(ButtonState::Down)) => {
-                    self.io.borrow_mut().port1 |= 1 << 0x04;
-                }
-                (Player::Player1, Button::Left(ButtonState::Up)) => {
-                    self.io.borrow_mut().port1 &= !(1 << 0x05);
-                }
-                (Player::Player1, Button::Right(ButtonState::Up)) => {
-                    self.io.borrow_mut().port1 &= !(1 << 0x06);
-                }
-                (Player::Player1, Button::Shoot(ButtonState::Up)) => {
-                    self.io.borrow_mut().port1 &= !(1 << 0x04);
-                }
-                (Player::Player1, Button::Coin(ButtonState::Up)) => {
-                    self.io.borrow_mut().port1 &= !(1 << 0x00);
-                }
-                (Player::Player1, Button::OnePlayer(ButtonState::Up)) => {
-                    self.io.borrow_mut().port1 &= !(1 << 0x02);
-                }
-                _ => {
-                    println!("Unhandled player/button combination")
-                }
+        for (player, button) in self.controller.get_button_states() {
+            let (port, bit, state) = match (player, button) {
+                (_, Button::Coin(state)) => (1, 0, state),
+                (_, Button::TwoPlayer(state)) => (1, 1, state),
+                (_, Button::OnePlayer(state)) => (1, 2, state),
+                (_, Button::Tilt(state)) => (2, 2, state),
+                (Player::Player1, Button::Shoot(state)) => (1, 4, state),
+                (Player::Player1, Button::Left(state)) => (1, 5, state),
+                (Player::Player1, Button::Right(state)) => (1, 6, state),
+                (Player::Player2, Button::Shoot(state)) => (2, 4, state),
+                (Player::Player2, Button::Left(state)) => (2, 5, state),
+                (Player::Player2, Button::Right(state)) => (2, 6, state)
+            };
+            let mut io = self.io.borrow_mut();
+            let port = if port == 1 { &mut io.port1 } else { &mut io.port2 };
+            match state {
+                ButtonState::Down => *port |= 1 << bit,
+                ButtonState::Up => *port &= !(1 << bit)
             }
         }
 
+        let flipped = self.io.borrow().flip_screen();
         self.screen.clear();
-        let framebuffer = &self.cpu.state.memory[0x2400..=0x3FFF];
-        for x in 0..224 {
-            let line = &framebuffer[(32 * x)..(32 * x + 32)];
-            for (i, px) in line.iter().enumerate() {
-                for b in 0..8 {
-                    if px & (1 << b) != 0 {
-                        let y = 256 - (8 * i + b) as i32;
-                        let color = if y > 180 {
-                            GREEN
-                        } else if y > 33 && y < 50 {
-                            RED
-                        } else {
-                            WHITE
-                        };
-                        self.screen.draw(x as i32, y, color);
-                    }
+        let screen = &mut self.screen;
+        draw_screen(&self.cpu.state.memory, &self.monitor, flipped, |x, y, color| screen.draw(x, y, color));
+        self.screen.present();
+    }
+}
+
+// Calls `draw` for every lit pixel of the video ram, rotated to the upright
+// monitor.
+fn render(memory: &[u8], mut draw: impl FnMut(i32, i32)) {
+    let framebuffer = &memory[0x2400..=0x3FFF];
+    for x in 0..SCREEN_WIDTH {
+        let line = &framebuffer[(32 * x)..(32 * x + 32)];
+        for (i, px) in line.iter().enumerate() {
+            for b in 0..8 {
+                if px & (1 << b) != 0 {
+                    let y = SCREEN_HEIGHT as i32 - 1 - (8 * i + b) as i32;
+                    draw(x as i32, y);
                 }
             }
         }
-        self.screen.present();
     }
-}
\ No newline at end of file
+}
+
+// Lit pixels in white, where they show in the picture on the host.
+fn draw_screen(memory: &[u8], monitor: &Monitor, flipped: bool, mut draw: impl FnMut(i32, i32, Color)) {
+    render(memory, |x, y| {
+        let (x, y) = monitor.glass(x, y, flipped);
+        let (screen_x, screen_y) = monitor.place(x, y);
+        draw(screen_x, screen_y, WHITE);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::machines::{IO, Speaker, Machine, Orientation, Monitor, Cabinet, DipSwitches, DipValue, find_machine};
+    use crate::machines::spaceinvaders::{SpaceInvaders, SpaceInvadersIO, SoundMap, info, overlay_presets};
+    use crate::state::{StateWriter, StateReader};
+    use crate::mixer::Mixer;
+    use crate::frontends::headless::{SilentSpeaker, NullScreen, NoInput};
+
+    struct RecordingSpeaker {
+        calls: Rc<RefCell<Vec<String>>>
+    }
+
+    impl Speaker for RecordingSpeaker {
+        fn start_wav_file(&mut self, file_name: &str) {
+            self.calls.borrow_mut().push(format!("start {}", file_name));
+        }
+        fn stop_wav_file(&mut self, file_name: &str) {
+            self.calls.borrow_mut().push(format!("stop {}", file_name));
+        }
+        fn play_wav_file(&mut self, file_name: &str) {
+            self.calls.borrow_mut().push(format!("play {}", file_name));
+        }
+    }
+
+    #[test]
+    fn fleet_march_and_amplifier() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut io = SpaceInvadersIO::new(Box::new(RecordingSpeaker { calls: calls.clone() }), &DipSwitches::default());
+        // muted: nothing plays
+        io.output(5, 0b0001);
+        io.output(3, 0b0000_0001);
+        assert!(calls.borrow().is_empty());
+        // amplifier on picks up the ufo loop that is already latched
+        io.output(3, 0b0010_0001);
+        for note in &[0b0010, 0b0100, 0b1000, 0b1_0000] {
+            io.output(5, *note);
+        }
+        assert_eq!(*calls.borrow(), vec![
+            "start ufo.wav",
+            "play fleet2.wav",
+            "play fleet3.wav",
+            "play fleet4.wav",
+            "play ufo_hit.wav"
+        ]);
+        // muting stops everything, not just the loop
+        calls.borrow_mut().clear();
+        io.output(3, 0b0000_0001);
+        let stops: Vec<String> = SoundMap::default().files().iter().map(|file| format!("stop {}", file)).collect();
+        assert_eq!(*calls.borrow(), stops);
+    }
+
+    #[test]
+    fn reset_and_loading_clear_the_latches() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut io = SpaceInvadersIO::new(Box::new(RecordingSpeaker { calls: calls.clone() }), &DipSwitches::default());
+        io.output(3, 0b0010_0001);
+        let mut state = StateWriter::new("test");
+        io.write_state(&mut state);
+        io.reset(&DipSwitches::default());
+        assert!(calls.borrow().contains(&"stop ufo.wav".to_string()));
+        // the ufo bit set again after the reset starts the loop again
+        calls.borrow_mut().clear();
+        io.output(3, 0b0010_0001);
+        assert_eq!(*calls.borrow(), vec!["start ufo.wav"]);
+
+        // loading stops what is playing and picks the loop up again
+        calls.borrow_mut().clear();
+        let bytes = state.finish();
+        io.read_state(&mut StateReader::new(&bytes, "test").unwrap()).unwrap();
+        let calls = calls.borrow();
+        assert_eq!(calls.last().unwrap(), "start ufo.wav");
+        assert!(calls.contains(&"stop shoot.wav".to_string()));
+    }
+
+    #[test]
+    fn cocktail_flip_and_orientation() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut io = SpaceInvadersIO::new(Box::new(RecordingSpeaker { calls }), &DipSwitches::default());
+        io.output(5, 0b10_0000);
+        assert!(io.flip_screen());
+        // only a cocktail table turns round
+        let upright = Monitor::new(&info());
+        let cocktail = Monitor { cabinet: Cabinet::Cocktail, ..upright };
+        assert_eq!(cocktail.glass(0, 0, true), (223, 255));
+        assert_eq!(cocktail.glass(0, 0, false), (0, 0));
+        assert_eq!(upright.glass(0, 0, true), (0, 0));
+        let landscape = Monitor { orientation: Orientation::Landscape, ..upright };
+        assert_eq!(landscape.size(), (256, 224));
+        assert_eq!(landscape.place(0, 10), (245, 0));
+        let mirrored = Monitor { mirror: true, ..landscape };
+        assert_eq!(mirrored.place(0, 10), (10, 0));
+
+        // the gels turn with the picture
+        let overlay = &overlay_presets()[2];
+        let placed = mirrored.place_overlay(overlay);
+        for (x, y) in [(30, 250), (20, 250), (0, 40), (100, 190)] {
+            let (screen_x, screen_y) = mirrored.place(x, y);
+            assert_eq!(placed.color_at(screen_x, screen_y), overlay.color_at(x, y));
+        }
+    }
+
+    #[test]
+    fn rom_set_is_checked() {
+        let info = find_machine("spaceinvaders").unwrap();
+        assert_eq!(info.screen_size(Orientation::Landscape), (256, 224));
+        assert_eq!(info.check_rom(&[0; 0x1000]), Err("Space Invaders needs 8192 bytes of ROM, got 4096".to_string()));
+        assert_eq!(info.check_rom(&[0; 0x2000]), Err("invaders.h doesn't match the Space Invaders ROM".to_string()));
+        assert!(find_machine("pacman").is_err());
+        let too_big = SpaceInvaders::new(vec![0; 0x10000], Box::new(SilentSpeaker), Box::new(NullScreen), Box::new(NoInput), DipSwitches::default());
+        assert_eq!(too_big.err(), Some("ROM: 65536 bytes at 0000 do not fit in memory".to_string()));
+    }
+
+    #[test]
+    fn default_samples_are_shipped() {
+        let mut mixer = Mixer::new();
+        for file_name in SoundMap::default().files() {
+            let bytes = std::fs::read(format!("../resources/spaceinvaders/{}", file_name)).unwrap();
+            assert_eq!(mixer.load_sample(file_name, &bytes), Ok(()));
+        }
+    }
+
+    #[test]
+    fn save_states_round_trip() {
+        let rom = std::fs::read("../resources/spaceinvaders/invaders").unwrap();
+        let new = || SpaceInvaders::new(rom.clone(), Box::new(SilentSpeaker), Box::new(NullScreen), Box::new(NoInput), DipSwitches::default()).unwrap();
+        let mut machine = new();
+        for _ in 0..100 {
+            machine.run_next_frame();
+        }
+        let state = machine.save_state();
+        machine.run_next_frame();
+        let next_frame = machine.frame_rgb();
+
+        let mut restored = new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        restored.run_next_frame();
+        assert_eq!(restored.frame_rgb(), next_frame);
+
+        // a broken state leaves the machine alone
+        assert!(restored.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(restored.frame_rgb(), next_frame);
+    }
+
+    #[test]
+    fn dip_switches_are_picked_up_on_reset() {
+        let rom = std::fs::read("../resources/spaceinvaders/invaders").unwrap();
+        let mut machine = SpaceInvaders::new(rom, Box::new(SilentSpeaker), Box::new(NullScreen), Box::new(NoInput), DipSwitches::default()).unwrap();
+        let dip_switches: DipSwitches = vec![
+            ("lives".to_string(), DipValue::Number(5)),
+            ("bonus_life".to_string(), DipValue::Number(1000)),
+            ("coin_info".to_string(), DipValue::Flag(false))
+        ].into_iter().collect();
+        assert_eq!(info().check_dip_switches(&dip_switches), Ok(()));
+        machine.set_dip_switches(&dip_switches);
+        assert_eq!(machine.io.borrow().input(2) & 0b1000_1011, 0);
+        machine.reset();
+        assert_eq!(machine.io.borrow().input(2) & 0b1000_1011, 0b1000_1010);
+    }
+}
@@ -0,0 +1,345 @@
+// An approximation of the Space Invaders sound boards, not a model of their
+// circuits: each sound is a plain oscillator or noise under a decay envelope,
+// tuned by ear to sound like the cabinet. Writes to the sound latches on
+// ports 3 and 5 are stamped with the cycle they happened on and rendered to
+// mono PCM at the end of each frame, so every sound starts and stops exactly
+// where the game toggled its bit.
+use std::f32::consts::PI;
+
+const FRAME_RATE: u32 = 60;
+
+// Pitches of the four notes of the fleet march, in the order the game plays them.
+const FLEET_NOTES: [f32; 4] = [98.0, 87.3, 77.8, 73.4];
+
+const AMP_ENABLE: u8 = 1 << 5;
+
+// Envelope level below which a sound is cut off.
+const SILENT: f32 = 1.0e-4;
+
+// White noise from a 17 bit shift register.
+struct Noise {
+    lfsr: u32
+}
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        let bit = (self.lfsr ^ (self.lfsr >> 3)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 16);
+        if self.lfsr & 1 == 1 { 1.0 } else { -1.0 }
+    }
+}
+
+// One pole low pass to take the edge off the noise.
+struct LowPass {
+    alpha: f32,
+    value: f32
+}
+
+impl LowPass {
+    fn new(cutoff: f32, sample_rate: f32) -> Self {
+        LowPass {
+            alpha: 1.0 - (-2.0 * PI * cutoff / sample_rate).exp(),
+            value: 0.0
+        }
+    }
+
+    fn next(&mut self, input: f32) -> f32 {
+        self.value += self.alpha * (input - self.value);
+        self.value
+    }
+}
+
+// Exponential fade fired by a latch bit going high.
+struct Decay {
+    level: f32,
+    factor: f32
+}
+
+impl Decay {
+    // `time` is how long it takes to fall to roughly 1/1000 of full volume.
+    fn new(time: f32, sample_rate: f32) -> Self {
+        Decay {
+            level: 0.0,
+            factor: (0.001f32.ln() / (time * sample_rate)).exp()
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.level = 1.0;
+    }
+
+    fn stop(&mut self) {
+        self.level = 0.0;
+    }
+
+    fn next(&mut self) -> f32 {
+        let level = self.level;
+        self.level *= self.factor;
+        // inaudible long before it would reach 0, and the sound stops here
+        if self.level < SILENT {
+            self.level = 0.0;
+        }
+        level
+    }
+}
+
+fn square(phase: f32) -> f32 {
+    if phase < 0.5 { 1.0 } else { -1.0 }
+}
+
+fn triangle(phase: f32) -> f32 {
+    if phase < 0.5 { 4.0 * phase - 1.0 } else { 3.0 - 4.0 * phase }
+}
+
+fn advance(phase: &mut f32, frequency: f32, sample_rate: f32) {
+    *phase = (*phase + frequency / sample_rate).fract();
+}
+
+pub struct Synth {
+    sample_rate: f32,
+    samples_per_frame: u32,
+    port3: u8,
+    port5: u8,
+    // latch writes for the current frame as (cycle, port, value)
+    events: Vec<(u64, u8, u8)>,
+    noise: Noise,
+
+    ufo_phase: f32,
+    ufo_lfo_phase: f32,
+
+    shot: Decay,
+    shot_phase: f32,
+    shot_filter: LowPass,
+
+    player_dies: Decay,
+    player_dies_filter: LowPass,
+
+    invader_dies: Decay,
+    invader_dies_phase: f32,
+    invader_dies_filter: LowPass,
+
+    extra_life: Decay,
+    extra_life_phase: f32,
+
+    fleet: Decay,
+    fleet_note: usize,
+    fleet_phase: f32,
+
+    ufo_hit: Decay,
+    ufo_hit_phase: f32,
+    ufo_hit_lfo_phase: f32
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f32;
+        Synth {
+            sample_rate: rate,
+            samples_per_frame: sample_rate / FRAME_RATE,
+            port3: 0,
+            port5: 0,
+            events: Vec::new(),
+            noise: Noise { lfsr: 1 },
+            ufo_phase: 0.0,
+            ufo_lfo_phase: 0.0,
+            shot: Decay::new(0.35, rate),
+            shot_phase: 0.0,
+            shot_filter: LowPass::new(4_000.0, rate),
+            player_dies: Decay::new(1.2, rate),
+            player_dies_filter: LowPass::new(600.0, rate),
+            invader_dies: Decay::new(0.3, rate),
+            invader_dies_phase: 0.0,
+            invader_dies_filter: LowPass::new(2_000.0, rate),
+            extra_life: Decay::new(1.0, rate),
+            extra_life_phase: 0.0,
+            fleet: Decay::new(0.15, rate),
+            fleet_note: 0,
+            fleet_phase: 0.0,
+            ufo_hit: Decay::new(1.0, rate),
+            ufo_hit_phase: 0.0,
+            ufo_hit_lfo_phase: 0.0
+        }
+    }
+
+    // Records a latch write `cycle` cycles into the current frame.
+    pub fn write(&mut self, port: u8, val: u8, cycle: u64) {
+        self.events.push((cycle, port, val));
+    }
+
+    // Renders one frame of audio, placing each latch write at its position
+    // within the `frame_cycles` cycles the frame took.
+    pub fn end_frame(&mut self, frame_cycles: u64, out: &mut Vec<i16>) {
+        let events = std::mem::take(&mut self.events);
+        let mut events = events.into_iter().peekable();
+        let samples = self.samples_per_frame as u64;
+        for i in 0..samples {
+            let cycle = i * frame_cycles.max(1) / samples;
+            while let Some((_, port, val)) = events.next_if(|(event_cycle, _, _)| *event_cycle <= cycle) {
+                self.latch(port, val);
+            }
+            let sample = (self.next_sample() * 0.8).clamp(-1.0, 1.0);
+            out.push((sample * i16::MAX as f32) as i16);
+        }
+        for (_, port, val) in events {
+            self.latch(port, val);
+        }
+    }
+
+    // Sets the latches without triggering anything, after a reset or when a
+    // state is loaded, with whatever was fading out stopped. The ufo picks up
+    // again if its bit is set.
+    pub fn restore(&mut self, port3: u8, port5: u8) {
+        self.events.clear();
+        self.stop_all();
+        self.port3 = port3;
+        self.port5 = port5;
+    }
+
+    fn stop_all(&mut self) {
+        for decay in [&mut self.shot, &mut self.player_dies, &mut self.invader_dies, &mut self.extra_life, &mut self.fleet, &mut self.ufo_hit] {
+            decay.stop();
+        }
+    }
+
+    fn latch(&mut self, port: u8, val: u8) {
+        let rising = |prev: u8, bit: u8| val & (1 << bit) != 0 && prev & (1 << bit) == 0;
+        match port {
+            3 => {
+                let prev = self.port3;
+                // switching the amplifier off cuts off what is still fading
+                if prev & AMP_ENABLE != 0 && val & AMP_ENABLE == 0 {
+                    self.stop_all();
+                }
+                if rising(prev, 1) {
+                    self.shot.trigger();
+                }
+                if rising(prev, 2) {
+                    self.player_dies.trigger();
+                }
+                if rising(prev, 3) {
+                    self.invader_dies.trigger();
+                }
+                if rising(prev, 4) {
+                    self.extra_life.trigger();
+                }
+                self.port3 = val;
+            }
+            5 => {
+                let prev = self.port5;
+                for note in 0..4 {
+                    if rising(prev, note as u8) {
+                        self.fleet_note = note;
+                        self.fleet.trigger();
+                    }
+                }
+                if rising(prev, 4) {
+                    self.ufo_hit.trigger();
+                }
+                self.port5 = val;
+            }
+            _ => {}
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let rate = self.sample_rate;
+        let noise = self.noise.next();
+        let mut mix = 0.0;
+
+        // ufo: a square wave swept up and down by a slow triangle
+        if self.port3 & 0x1 != 0 {
+            advance(&mut self.ufo_lfo_phase, 6.0, rate);
+            let frequency = 750.0 + 250.0 * triangle(self.ufo_lfo_phase);
+            advance(&mut self.ufo_phase, frequency, rate);
+            mix += 0.25 * square(self.ufo_phase);
+        }
+
+        // shot: filtered noise over a falling tone
+        let level = self.shot.next();
+        if level > 0.0 {
+            advance(&mut self.shot_phase, 400.0 + 1_200.0 * level, rate);
+            let hiss = self.shot_filter.next(noise);
+            mix += level * (0.3 * hiss + 0.2 * triangle(self.shot_phase));
+        }
+
+        // player dies: long low rumble of noise
+        let level = self.player_dies.next();
+        mix += level * 0.9 * self.player_dies_filter.next(noise);
+
+        // invader dies: short crunch of noise and a dropping tone
+        let level = self.invader_dies.next();
+        if level > 0.0 {
+            advance(&mut self.invader_dies_phase, 200.0 + 600.0 * level, rate);
+            let crunch = self.invader_dies_filter.next(noise);
+            mix += level * (0.35 * crunch + 0.25 * square(self.invader_dies_phase));
+        }
+
+        // extra life: a rapid beep
+        let level = self.extra_life.next();
+        if level > 0.0 {
+            advance(&mut self.extra_life_phase, 1_000.0, rate);
+            let chop = if (level * 16.0) as u32 % 2 == 0 { 1.0 } else { 0.0 };
+            mix += level * chop * 0.2 * square(self.extra_life_phase);
+        }
+
+        // fleet march: one of four low thumps
+        let level = self.fleet.next();
+        if level > 0.0 {
+            advance(&mut self.fleet_phase, FLEET_NOTES[self.fleet_note], rate);
+            mix += level * 0.5 * square(self.fleet_phase);
+        }
+
+        // ufo hit: a fast warble dying away
+        let level = self.ufo_hit.next();
+        if level > 0.0 {
+            advance(&mut self.ufo_hit_lfo_phase, 24.0, rate);
+            let frequency = 1_200.0 + 400.0 * triangle(self.ufo_hit_lfo_phase);
+            advance(&mut self.ufo_hit_phase, frequency, rate);
+            mix += level * 0.25 * triangle(self.ufo_hit_phase);
+        }
+
+        // the amplifier enable bit gates everything
+        if self.port3 & AMP_ENABLE == 0 {
+            0.0
+        } else {
+            mix
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::machines::spaceinvaders::synth::{Synth, Decay};
+    use crate::mixer::SAMPLE_RATE;
+
+    #[test]
+    fn latch_writes_start_sound_mid_frame() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.write(3, 0b0010_0000, 0);
+        synth.write(5, 0b0001, 25_000);
+        let mut out = Vec::new();
+        synth.end_frame(50_000, &mut out);
+        assert_eq!(out.len(), (SAMPLE_RATE / 60) as usize);
+        let half = out.len() / 2;
+        assert!(out[..half - 1].iter().all(|s| *s == 0));
+        assert!(out[half..].iter().any(|s| *s != 0));
+    }
+
+    #[test]
+    fn sounds_fade_out_to_nothing() {
+        let mut decay = Decay::new(0.01, 1_000.0);
+        decay.trigger();
+        let levels: Vec<f32> = (0..20).map(|_| decay.next()).collect();
+        assert_eq!(levels[0], 1.0);
+        assert_eq!(levels[19], 0.0);
+    }
+
+    #[test]
+    fn amplifier_off_is_silent() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.write(3, 0b0000_0011, 0);
+        let mut out = Vec::new();
+        synth.end_frame(50_000, &mut out);
+        assert!(out.iter().all(|s| *s == 0));
+    }
+}
@@ -1,13 +1,154 @@
-mod cpu;
-mod machines;
+mod cli;
+use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use emulator::config::Config;
+use emulator::machines::{MachineInfo, DipValue, machines, find_machine};
+use emulator::frontends::Options;
+use emulator::resources::default_resources;
+use emulator::frontends::headless::HeadlessFrontend;
+#[cfg(feature = "sdl")]
+use emulator::frontends::sdl::SdlFrontend;
+#[cfg(feature = "tui")]
+use emulator::frontends::tui::TuiFrontend;
+use cli::{Cli, Command, USAGE};
+
 fn main() {
-    let result = fs::read("resources/spaceinvaders/invaders");
-    if let Ok(bytes) = result {
-        let space_invaders = machines::spaceinvaders::SpaceInvaders::new(bytes);
-        space_invaders.play();
-    } else {
-        println!("Error reading file {:?}", result);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match Cli::parse(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(cli) {
+        println!("{}", e);
+        process::exit(1);
     }
 }
- 
\ No newline at end of file
+
+fn run(cli: Cli) -> Result<(), String> {
+    match cli.command {
+        Command::Help => {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+        Command::Machines => {
+            for info in machines() {
+                println!("{:16} {}", info.name, info.title);
+            }
+            return Ok(());
+        }
+        Command::Run | Command::Headless => {}
+    }
+    let mut config = Config::load_or_create(&cli.config).map_err(|e| format!("Error reading config {}", e))?;
+    if cli.verbose {
+        println!("Read config from {}", cli.config.display());
+    }
+    if let Some(machine) = &cli.machine {
+        config.machine = machine.clone();
+    }
+    if let Some(scale) = cli.scale {
+        config.scale = scale;
+    }
+    if cli.fullscreen {
+        config.fullscreen = true;
+    }
+    if cli.mute {
+        config.volume = 0;
+    }
+    if let Some(lives) = cli.lives {
+        config.dip_switches.insert("lives".to_string(), DipValue::Number(lives as u16));
+    }
+    if let Some(bonus_life) = cli.bonus_life {
+        config.dip_switches.insert("bonus_life".to_string(), DipValue::Number(bonus_life));
+    }
+    if let Some(coin_info) = cli.coin_info {
+        config.dip_switches.insert("coin_info".to_string(), DipValue::Flag(coin_info));
+    }
+    let info = find_machine(&config.machine)?;
+    info.check_dip_switches(&config.dip_switches)?;
+    // the ROM from the command line or config wins over the machine's own
+    let rom = cli.rom.clone().or_else(|| config.rom.as_ref().map(PathBuf::from));
+    let bytes = match &rom {
+        Some(rom) => load_rom(&info, rom, &cli)?,
+        None => default_resources().load(info.name, info.rom)
+            .map_err(|e| format!("Can't read the {} ROM from {}\n{}", info.title, e, rom_hint(&info, &cli)))?
+    };
+    let source = rom.map(|rom| rom.display().to_string()).unwrap_or_else(|| "its resources".to_string());
+    // a patched or homebrew ROM is allowed to differ
+    match info.check_rom(&bytes) {
+        Ok(()) if cli.verbose => println!("Loaded the {} ROM from {}", info.title, source),
+        Ok(()) => {}
+        Err(e) => println!("Warning: {}", e)
+    }
+    match cli.command {
+        Command::Headless => {
+            let frames = cli.frames.unwrap_or(0);
+            if cli.verbose {
+                println!("Running {} frames headless", frames);
+            }
+            HeadlessFrontend::new(bytes, &config, &cli.options, cli.save_state.clone())?.run(frames)
+        }
+        _ if cli.tui => run_tui(bytes, &config, &cli.options),
+        _ => run_sdl(bytes, &config, &cli.options)
+    }
+}
+
+// `path` is the ROM file or a directory holding it, or holding the chips as
+// separate files.
+fn load_rom(info: &MachineInfo, path: &Path, cli: &Cli) -> Result<Vec<u8>, String> {
+    let chips: Vec<&str> = info.roms.iter().map(|rom| rom.name).collect();
+    let hint = rom_hint(info, cli);
+    if path.is_dir() {
+        let combined = path.join(info.rom);
+        if combined.is_file() {
+            return fs::read(&combined).map_err(|e| format!("Error reading {}: {}", combined.display(), e));
+        }
+        let mut bytes = Vec::new();
+        for chip in chips.iter() {
+            match fs::read(path.join(chip)) {
+                Ok(chip) => bytes.extend(chip),
+                Err(_) => return Err(format!("Can't find the {} ROM in {}\n{}", info.title, path.display(), hint))
+            }
+        }
+        return Ok(bytes);
+    }
+    fs::read(path).map_err(|e| {
+        let working_directory = env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_default();
+        format!("Can't read the {} ROM at {} from {}: {}\n{}", info.title, path.display(), working_directory, e, hint)
+    })
+}
+
+fn rom_hint(info: &MachineInfo, cli: &Cli) -> String {
+    let chips: Vec<&str> = info.roms.iter().map(|rom| rom.name).collect();
+    format!(
+        "Pass --rom with the ROM file or a directory holding {} or the chips {}, or set rom in {}",
+        info.rom, chips.join(", "), cli.config.display()
+    )
+}
+
+#[cfg(feature = "sdl")]
+fn run_sdl(bytes: Vec<u8>, config: &Config, options: &Options) -> Result<(), String> {
+    let frontend = SdlFrontend::new(bytes, config, options).map_err(|e| format!("Error starting SDL frontend {}", e))?;
+    frontend.play();
+    Ok(())
+}
+
+#[cfg(not(feature = "sdl"))]
+fn run_sdl(_: Vec<u8>, _: &Config, _: &Options) -> Result<(), String> {
+    Err("Built without the SDL frontend, rebuild with --features sdl or run with --tui".to_string())
+}
+
+#[cfg(feature = "tui")]
+fn run_tui(bytes: Vec<u8>, config: &Config, options: &Options) -> Result<(), String> {
+    let frontend = TuiFrontend::new(bytes, config, options).map_err(|e| format!("Error starting terminal frontend {}", e))?;
+    frontend.play()
+}
+
+#[cfg(not(feature = "tui"))]
+fn run_tui(_: Vec<u8>, _: &Config, _: &Options) -> Result<(), String> {
+    Err("Built without the terminal frontend, rebuild with --features tui".to_string())
+}
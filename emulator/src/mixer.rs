@@ -0,0 +1,228 @@
+// Mixes sound samples and streamed PCM into one interleaved stereo stream,
+// one frame at a time. Voices are started and stopped at the cycle within the
+// frame they were triggered on, so the result is the same however fast the
+// frontend asks for frames.
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::wav;
+
+pub const SAMPLE_RATE: u32 = 44_100;
+pub const CHANNELS: usize = 2;
+const FRAME_RATE: u32 = 60;
+
+struct Voice {
+    name: String,
+    sample: Rc<Vec<f32>>,
+    position: usize,
+    looping: bool,
+    volume: f32
+}
+
+enum Command {
+    Play { name: String, looping: bool, volume: f32 },
+    Stop { name: String },
+    StopAll
+}
+
+pub struct Mixer {
+    // mono, already resampled to SAMPLE_RATE
+    samples: HashMap<String, Rc<Vec<f32>>>,
+    voices: Vec<Voice>,
+    commands: Vec<(u64, Command)>,
+    // mono PCM streamed in for the current frame, e.g. from a synthesizer
+    stream: Vec<f32>,
+    volume: f32,
+    samples_per_frame: usize
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Mixer {
+            samples: HashMap::new(),
+            voices: Vec::new(),
+            commands: Vec::new(),
+            stream: Vec::new(),
+            volume: 1.0,
+            samples_per_frame: (SAMPLE_RATE / FRAME_RATE) as usize
+        }
+    }
+
+    pub fn samples_per_frame(&self) -> usize {
+        self.samples_per_frame
+    }
+
+    // Decodes a WAV file and keeps it under `name`, mixed down to mono and
+    // resampled to the mixer's rate.
+    pub fn load_sample(&mut self, name: &str, bytes: &[u8]) -> Result<(), String> {
+        let wav = wav::decode(bytes)?;
+        let channels = wav.channels as usize;
+        let mono: Vec<f32> = wav.samples.chunks(channels)
+            .map(|frame| frame.iter().map(|s| *s as f32 / 32_768.0).sum::<f32>() / channels as f32)
+            .collect();
+        let step = wav.sample_rate as f64 / SAMPLE_RATE as f64;
+        let len = (mono.len() as f64 / step) as usize;
+        let resampled = (0..len).map(|i| {
+            let position = i as f64 * step;
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let a = mono[index];
+            let b = *mono.get(index + 1).unwrap_or(&a);
+            a + (b - a) * fraction
+        }).collect();
+        self.samples.insert(name.to_string(), Rc::new(resampled));
+        Ok(())
+    }
+
+    // 0.0 to 1.0, applied to the whole mix
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    // Starts `name` `cycle` cycles into the current frame. Voices overlap,
+    // except that a looping sample that is already playing isn't started twice.
+    pub fn play(&mut self, name: &str, cycle: u64, looping: bool, volume: f32) {
+        self.commands.push((cycle, Command::Play { name: name.to_string(), looping, volume }));
+    }
+
+    pub fn stop(&mut self, name: &str, cycle: u64) {
+        self.commands.push((cycle, Command::Stop { name: name.to_string() }));
+    }
+
+    // Cuts off every voice, looping or not.
+    pub fn stop_all(&mut self, cycle: u64) {
+        self.commands.push((cycle, Command::StopAll));
+    }
+
+    // Adds mono PCM at SAMPLE_RATE to the current frame.
+    pub fn stream(&mut self, samples: &[i16]) {
+        self.stream.extend(samples.iter().map(|s| *s as f32 / 32_768.0));
+    }
+
+    // Renders the frame that took `frame_cycles` cycles, appending interleaved
+    // stereo samples to `out`.
+    pub fn mix_frame(&mut self, frame_cycles: u64, out: &mut Vec<i16>) {
+        let mut commands = std::mem::take(&mut self.commands);
+        // stable, so commands on the same cycle keep their order
+        commands.sort_by_key(|(cycle, _)| *cycle);
+        let mut commands = commands.into_iter().peekable();
+        for i in 0..self.samples_per_frame {
+            let cycle = i as u64 * frame_cycles.max(1) / self.samples_per_frame as u64;
+            while let Some((_, command)) = commands.next_if(|(command_cycle, _)| *command_cycle <= cycle) {
+                self.apply(command);
+            }
+            let mut mix = self.stream.get(i).copied().unwrap_or(0.0);
+            for voice in self.voices.iter_mut() {
+                mix += voice.sample[voice.position] * voice.volume;
+                voice.position += 1;
+                if voice.position == voice.sample.len() && voice.looping {
+                    voice.position = 0;
+                }
+            }
+            self.voices.retain(|voice| voice.position < voice.sample.len());
+            let sample = ((mix * self.volume).clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            for _ in 0..CHANNELS {
+                out.push(sample);
+            }
+        }
+        for (_, command) in commands {
+            self.apply(command);
+        }
+        self.stream.clear();
+    }
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::Play { name, looping, volume } => {
+                if looping && self.voices.iter().any(|voice| voice.looping && voice.name == name) {
+                    return;
+                }
+                if let Some(sample) = self.samples.get(&name) {
+                    if !sample.is_empty() {
+                        self.voices.push(Voice {
+                            name,
+                            sample: sample.clone(),
+                            position: 0,
+                            looping,
+                            volume
+                        });
+                    }
+                }
+            }
+            Command::Stop { name } => {
+                self.voices.retain(|voice| voice.name != name);
+            }
+            Command::StopAll => self.voices.clear()
+        }
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mixer::{Mixer, CHANNELS};
+
+    // 8 bit mono WAV holding `len` samples at full positive level
+    fn wav(sample_rate: u32, len: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + len as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0, 1, 0]);
+        bytes.extend_from_slice(&sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0, 8, 0]);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(len as u32).to_le_bytes());
+        bytes.extend(std::iter::repeat(192).take(len));
+        bytes
+    }
+
+    #[test]
+    fn voices_overlap_and_start_on_their_cycle() {
+        let mut mixer = Mixer::new();
+        mixer.load_sample("beep", &wav(44_100, 100)).unwrap();
+        mixer.play("beep", 0, false, 0.5);
+        mixer.play("beep", 500, false, 0.5);
+        let mut out = Vec::new();
+        mixer.mix_frame(1_000, &mut out);
+        assert_eq!(out.len(), mixer.samples_per_frame() * CHANNELS);
+        let left: Vec<i16> = out.iter().step_by(CHANNELS).copied().collect();
+        let half = mixer.samples_per_frame() / 2;
+        // only the first voice
+        assert!(left[10] > 0);
+        assert_eq!(left[10], left[50]);
+        // the first voice has finished before the second starts halfway through
+        assert_eq!(left[half - 1], 0);
+        assert_eq!(left[half + 1], left[10]);
+    }
+
+    #[test]
+    fn loops_until_stopped() {
+        let mut mixer = Mixer::new();
+        mixer.load_sample("ufo", &wav(11_025, 10)).unwrap();
+        mixer.play("ufo", 0, true, 1.0);
+        mixer.play("ufo", 0, true, 1.0);
+        let mut out = Vec::new();
+        mixer.mix_frame(1_000, &mut out);
+        assert!(out.iter().all(|s| *s > 0));
+        // a second start of the same loop is ignored rather than doubling the volume
+        assert!(out.iter().all(|s| *s == out[0]));
+        out.clear();
+        mixer.stop("ufo", 0);
+        mixer.mix_frame(1_000, &mut out);
+        assert!(out.iter().all(|s| *s == 0));
+
+        mixer.play("ufo", 0, true, 1.0);
+        mixer.stop_all(500);
+        out.clear();
+        mixer.mix_frame(1_000, &mut out);
+        assert!(out[0] > 0);
+        assert_eq!(*out.last().unwrap(), 0);
+    }
+}
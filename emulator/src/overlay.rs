@@ -0,0 +1,127 @@
+// Colour overlays: strips of coloured cellophane ("gels") stuck over a black
+// and white monitor. The machine draws in black and white; the frontend puts
+// the overlay in front to decide what colour the lit pixels show up as.
+use serde::{Serialize, Deserialize};
+use crate::machines::Screen;
+
+pub type Color = (u8, u8, u8);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Gel {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub color: Color
+}
+
+impl Gel {
+    fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Overlay {
+    pub name: String,
+    // colour of lit pixels no gel covers
+    pub color: Color,
+    // later gels are stuck on top of earlier ones
+    #[serde(default)]
+    pub gels: Vec<Gel>
+}
+
+impl Overlay {
+    pub fn color_at(&self, x: i32, y: i32) -> Color {
+        if x < 0 || y < 0 {
+            return self.color;
+        }
+        self.gels.iter().rev()
+            .find(|gel| gel.contains(x as u32, y as u32))
+            .map(|gel| gel.color)
+            .unwrap_or(self.color)
+    }
+
+    // What a pixel of `color` looks like through the gels.
+    pub fn tint(&self, x: i32, y: i32, color: Color) -> Color {
+        let gel = self.color_at(x, y);
+        let filter = |light: u8, gel: u8| (light as u16 * gel as u16 / 255) as u8;
+        (filter(color.0, gel.0), filter(color.1, gel.1), filter(color.2, gel.2))
+    }
+
+    // Tints a whole RGB frame `width` pixels wide.
+    pub fn tint_rgb(&self, rgb: &mut [u8], width: usize) {
+        for (i, pixel) in rgb.chunks_exact_mut(3).enumerate() {
+            let (r, g, b) = self.tint((i % width) as i32, (i / width) as i32, (pixel[0], pixel[1], pixel[2]));
+            pixel.copy_from_slice(&[r, g, b]);
+        }
+    }
+
+    // Looks `name` up in `custom` first, so a config can replace a preset.
+    pub fn find(name: &str, custom: &[Overlay], presets: &[Overlay]) -> Result<Overlay, String> {
+        custom.iter().chain(presets.iter())
+            .find(|overlay| overlay.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<&str> = custom.iter().chain(presets.iter()).map(|overlay| overlay.name.as_str()).collect();
+                format!("unknown overlay {}, expected one of {}", name, names.join(", "))
+            })
+    }
+}
+
+// A screen with the overlay stuck in front of it.
+pub struct OverlayScreen {
+    screen: Box<dyn Screen>,
+    overlay: Overlay
+}
+
+impl OverlayScreen {
+    pub fn new(screen: Box<dyn Screen>, overlay: Overlay) -> Self {
+        OverlayScreen { screen, overlay }
+    }
+}
+
+impl Screen for OverlayScreen {
+    fn clear(&mut self) {
+        self.screen.clear();
+    }
+
+    fn draw(&mut self, x: i32, y: i32, color: Color) {
+        self.screen.draw(x, y, self.overlay.tint(x, y, color));
+    }
+
+    fn present(&mut self) {
+        self.screen.present();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::overlay::{Overlay, Gel};
+
+    #[test]
+    fn later_gels_win_and_custom_overlays_replace_presets() {
+        let overlay = Overlay {
+            name: "test".to_string(),
+            color: (255, 255, 255),
+            gels: vec![
+                Gel { x: 0, y: 10, width: 10, height: 10, color: (0, 255, 0) },
+                Gel { x: 5, y: 15, width: 10, height: 10, color: (255, 0, 0) }
+            ]
+        };
+        assert_eq!(overlay.color_at(0, 0), (255, 255, 255));
+        assert_eq!(overlay.color_at(0, 10), (0, 255, 0));
+        assert_eq!(overlay.color_at(5, 15), (255, 0, 0));
+        assert_eq!(overlay.color_at(10, 10), (255, 255, 255));
+        assert_eq!(overlay.tint(5, 15, (128, 128, 128)), (128, 0, 0));
+
+        // gels reaching past the edge of a u32 don't overflow
+        let wide = Overlay { gels: vec![Gel { x: 1, y: 1, width: u32::MAX, height: u32::MAX, color: (0, 0, 255) }], ..overlay.clone() };
+        assert_eq!(wide.color_at(1000, 1000), (0, 0, 255));
+        assert_eq!(wide.color_at(0, 1000), (255, 255, 255));
+
+        let presets = vec![Overlay { name: "test".to_string(), color: (0, 0, 0), gels: Vec::new() }];
+        assert_eq!(Overlay::find("test", std::slice::from_ref(&overlay), &presets), Ok(overlay));
+        assert!(Overlay::find("missing", &[], &presets).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+// Records the buttons pressed on each frame to a text file and plays them
+// back. The machines are deterministic, so a replay started from the same
+// point (power on or the same save state) plays out exactly the same way.
+// One line per button change: frame, player, button and down or up, e.g.
+// `120 Player1 Coin down`.
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use crate::machines::{Controller, Button, ButtonState, Player};
+use crate::input::ButtonKind;
+
+const PLAYERS: [Player; 2] = [Player::Player1, Player::Player2];
+const BUTTONS: [ButtonKind; 7] = [
+    ButtonKind::Shoot,
+    ButtonKind::Left,
+    ButtonKind::Right,
+    ButtonKind::Coin,
+    ButtonKind::OnePlayer,
+    ButtonKind::TwoPlayer,
+    ButtonKind::Tilt
+];
+
+// Passes the buttons of `controller` through, writing them down as it goes.
+pub struct InputRecorder {
+    controller: Box<dyn Controller>,
+    out: BufWriter<File>,
+    frame: u64
+}
+
+impl InputRecorder {
+    pub fn create(path: &Path, controller: Box<dyn Controller>) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(InputRecorder {
+            controller,
+            out: BufWriter::new(file),
+            frame: 0
+        })
+    }
+}
+
+impl Controller for InputRecorder {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        let buttons = self.controller.get_button_states();
+        for (player, button) in buttons.iter() {
+            let (kind, state) = ButtonKind::of(*button);
+            let state = if state == ButtonState::Down { "down" } else { "up" };
+            if let Err(e) = writeln!(self.out, "{} {:?} {:?} {}", self.frame, player, kind, state) {
+                println!("Error recording input {}", e);
+            }
+        }
+        self.frame += 1;
+        buttons
+    }
+}
+
+impl Drop for InputRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.out.flush() {
+            println!("Error recording input {}", e);
+        }
+    }
+}
+
+// Plays a recording back. `controller` is still polled, so hotkeys and
+// closing the window work, but its buttons are ignored.
+pub struct InputPlayer {
+    controller: Box<dyn Controller>,
+    events: Vec<(u64, Player, Button)>,
+    next: usize,
+    frame: u64
+}
+
+impl InputPlayer {
+    pub fn open(path: &Path, controller: Box<dyn Controller>) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let events = parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(InputPlayer {
+            controller,
+            events,
+            next: 0,
+            frame: 0
+        })
+    }
+}
+
+impl Controller for InputPlayer {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        self.controller.get_button_states();
+        let mut buttons = Vec::new();
+        while let Some((frame, player, button)) = self.events.get(self.next) {
+            if *frame > self.frame {
+                break;
+            }
+            buttons.push((*player, *button));
+            self.next += 1;
+        }
+        self.frame += 1;
+        buttons
+    }
+}
+
+fn parse(text: &str) -> Result<Vec<(u64, Player, Button)>, String> {
+    let mut events = Vec::new();
+    for (i, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let error = || format!("line {}: expected a frame, player, button and down or up", i + 1);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err(error());
+        }
+        let frame = fields[0].parse::<u64>().map_err(|_| error())?;
+        let player = PLAYERS.iter().find(|player| format!("{:?}", player) == fields[1]).ok_or_else(error)?;
+        let kind = BUTTONS.iter().find(|kind| format!("{:?}", kind) == fields[2]).ok_or_else(error)?;
+        let state = match fields[3] {
+            "down" => ButtonState::Down,
+            "up" => ButtonState::Up,
+            _ => return Err(error())
+        };
+        events.push((frame, *player, kind.with_state(state)));
+    }
+    events.sort_by_key(|(frame, _, _)| *frame);
+    Ok(events)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::machines::{Button, ButtonState, Player};
+    use crate::replay::parse;
+
+    #[test]
+    fn recordings_parse_in_frame_order() {
+        let events = parse("3 Player2 Shoot up\n\n1 Player2 Shoot down\n").unwrap();
+        assert_eq!(events, vec![
+            (1, Player::Player2, Button::Shoot(ButtonState::Down)),
+            (3, Player::Player2, Button::Shoot(ButtonState::Up))
+        ]);
+        assert_eq!(parse("1 Player3 Shoot down"), Err("line 1: expected a frame, player, button and down or up".to_string()));
+    }
+}
@@ -0,0 +1,152 @@
+// Where a machine's ROM and samples come from: files on disk, files fetched
+// relative to the web page, or copies compiled into the binary with the
+// `embedded` feature. Files are named as in the machine's directory, e.g.
+// `load("spaceinvaders", "ufo.wav")`.
+use std::env;
+use std::path::PathBuf;
+#[cfg(feature = "web")]
+use std::collections::HashMap;
+#[cfg(feature = "web")]
+use wasm_bindgen::JsCast;
+#[cfg(feature = "web")]
+use wasm_bindgen_futures::JsFuture;
+
+pub trait Resources {
+    fn load(&self, machine: &str, file_name: &str) -> Result<Vec<u8>, String>;
+}
+
+// For frontends that don't use any files.
+pub struct NoResources;
+
+impl Resources for NoResources {
+    fn load(&self, machine: &str, file_name: &str) -> Result<Vec<u8>, String> {
+        Err(format!("{}/{}: no resources in this frontend", machine, file_name))
+    }
+}
+
+// resources/<machine>/<file>, looked for in the working directory and then
+// next to the executable and above it, so a build run from another directory
+// still finds its files.
+pub struct FileResources {
+    root: PathBuf
+}
+
+impl FileResources {
+    pub fn new(root: PathBuf) -> Self {
+        FileResources {
+            root
+        }
+    }
+
+    pub fn find() -> Self {
+        let mut candidates = vec![PathBuf::from("resources")];
+        if let Ok(executable) = env::current_exe() {
+            candidates.extend(executable.ancestors().skip(1).map(|dir| dir.join("resources")));
+        }
+        let root = candidates.into_iter()
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| PathBuf::from("resources"));
+        FileResources::new(root)
+    }
+}
+
+impl Resources for FileResources {
+    fn load(&self, machine: &str, file_name: &str) -> Result<Vec<u8>, String> {
+        let path = self.root.join(machine).join(file_name);
+        std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+// Files fetched over HTTP from `base`, which can be relative to the page,
+// e.g. `../resources/`. Loading can't wait for the network, so the files
+// have to be fetched before the machine starts.
+#[cfg(feature = "web")]
+pub struct HttpResources {
+    base: String,
+    files: HashMap<String, Vec<u8>>
+}
+
+#[cfg(feature = "web")]
+impl HttpResources {
+    pub fn new(base: &str) -> Self {
+        let base = if base.ends_with('/') { base.to_string() } else { format!("{}/", base) };
+        HttpResources {
+            base,
+            files: HashMap::new()
+        }
+    }
+
+    pub fn url(&self, machine: &str, file_name: &str) -> String {
+        format!("{}{}/{}", self.base, machine, file_name)
+    }
+
+    pub async fn fetch(&mut self, machine: &str, file_name: &str) -> Result<(), String> {
+        let url = self.url(machine, file_name);
+        let window = web_sys::window().ok_or("no window to fetch from")?;
+        let response = JsFuture::from(window.fetch_with_str(&url)).await
+            .map_err(|_| format!("{}: couldn't be fetched", url))?;
+        let response: web_sys::Response = response.dyn_into().map_err(|_| format!("{}: not a response", url))?;
+        if !response.ok() {
+            return Err(format!("{}: {} {}", url, response.status(), response.status_text()));
+        }
+        let buffer = response.array_buffer().map_err(|_| format!("{}: couldn't be read", url))?;
+        let buffer = JsFuture::from(buffer).await.map_err(|_| format!("{}: couldn't be read", url))?;
+        self.files.insert(url, js_sys::Uint8Array::new(&buffer).to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "web")]
+impl Resources for HttpResources {
+    fn load(&self, machine: &str, file_name: &str) -> Result<Vec<u8>, String> {
+        let url = self.url(machine, file_name);
+        self.files.get(&url).cloned().ok_or_else(|| format!("{}: not fetched", url))
+    }
+}
+
+#[cfg(feature = "embedded")]
+macro_rules! embed {
+    ($machine:literal, $file_name:literal) => {
+        ($machine, $file_name, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/../resources/", $machine, "/", $file_name)))
+    };
+}
+
+// Everything under resources/ at build time.
+#[cfg(feature = "embedded")]
+const EMBEDDED: [(&str, &str, &[u8]); 11] = [
+    embed!("spaceinvaders", "invaders"),
+    embed!("spaceinvaders", "ufo.wav"),
+    embed!("spaceinvaders", "shoot.wav"),
+    embed!("spaceinvaders", "player_dies.wav"),
+    embed!("spaceinvaders", "invader_dies.wav"),
+    embed!("spaceinvaders", "extra_life.wav"),
+    embed!("spaceinvaders", "bomp.wav"),
+    embed!("spaceinvaders", "fleet2.wav"),
+    embed!("spaceinvaders", "fleet3.wav"),
+    embed!("spaceinvaders", "fleet4.wav"),
+    embed!("spaceinvaders", "ufo_hit.wav")
+];
+
+#[cfg(feature = "embedded")]
+pub struct EmbeddedResources;
+
+#[cfg(feature = "embedded")]
+impl Resources for EmbeddedResources {
+    fn load(&self, machine: &str, file_name: &str) -> Result<Vec<u8>, String> {
+        EMBEDDED.iter()
+            .find(|(embedded_machine, embedded_file, _)| *embedded_machine == machine && *embedded_file == file_name)
+            .map(|(_, _, bytes)| bytes.to_vec())
+            .ok_or_else(|| format!("{}/{}: not built in", machine, file_name))
+    }
+}
+
+// The compiled in files with the `embedded` feature, otherwise the ones on disk.
+#[cfg(feature = "embedded")]
+pub fn default_resources() -> Box<dyn Resources> {
+    Box::new(EmbeddedResources)
+}
+
+#[cfg(not(feature = "embedded"))]
+pub fn default_resources() -> Box<dyn Resources> {
+    Box::new(FileResources::find())
+}
@@ -0,0 +1,116 @@
+// Save states: a header naming the machine, then each part of the machine
+// writes its fields in turn and reads them back in the same order.
+const MAGIC: &[u8; 4] = b"8080";
+const VERSION: u8 = 1;
+
+pub struct StateWriter {
+    bytes: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new(machine: &str) -> Self {
+        let mut writer = StateWriter {
+            bytes: MAGIC.to_vec()
+        };
+        writer.u8(VERSION);
+        writer.u8(machine.len() as u8);
+        writer.bytes(machine.as_bytes());
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl<'a> StateReader<'a> {
+    // Fails unless `bytes` is a save state of `machine` in this version.
+    pub fn new(bytes: &'a [u8], machine: &str) -> Result<Self, String> {
+        let mut reader = StateReader {
+            bytes,
+            offset: 0
+        };
+        if reader.bytes(MAGIC.len()) != Ok(&MAGIC[..]) {
+            return Err("not a save state".to_string());
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("save state version {} isn't supported, expected {}", version, VERSION));
+        }
+        let length = reader.u8()? as usize;
+        let name = String::from_utf8_lossy(reader.bytes(length)?).to_string();
+        if name != machine {
+            return Err(format!("save state is for {}, not {}", name, machine));
+        }
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.offset + length;
+        if end > self.bytes.len() {
+            return Err("save state is cut short".to_string());
+        }
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::{StateWriter, StateReader};
+
+    #[test]
+    fn fields_round_trip_and_the_header_is_checked() {
+        let mut writer = StateWriter::new("test");
+        writer.u8(1);
+        writer.u16(0x1234);
+        writer.bool(true);
+        writer.bytes(&[5, 6]);
+        let bytes = writer.finish();
+
+        let mut reader = StateReader::new(&bytes, "test").unwrap();
+        assert_eq!(reader.u8(), Ok(1));
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.bytes(2), Ok(&[5, 6][..]));
+        assert!(reader.u8().is_err());
+
+        assert_eq!(StateReader::new(&bytes, "other").err(), Some("save state is for test, not other".to_string()));
+        assert_eq!(StateReader::new(b"junk", "test").err(), Some("not a save state".to_string()));
+    }
+}
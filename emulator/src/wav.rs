@@ -0,0 +1,154 @@
+// Just enough of the RIFF WAVE format for the uncompressed PCM samples the
+// machines ship with and for recording the mixed output.
+use std::fs::File;
+use std::convert::TryFrom;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub struct Wav {
+    pub sample_rate: u32,
+    pub channels: u16,
+    // interleaved when there is more than one channel
+    pub samples: Vec<i16>
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Wav, String> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err("not a RIFF WAVE file".to_string());
+    }
+    let mut format = None;
+    let mut data = None;
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let size = u32::from_le_bytes([bytes[offset + 4], bytes[offset + 5], bytes[offset + 6], bytes[offset + 7]]) as usize;
+        let body = &bytes[offset + 8..bytes.len().min(offset + 8 + size)];
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let audio_format = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]);
+                let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                format = Some((audio_format, channels, sample_rate, bits));
+            }
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        // chunks are padded to an even length
+        offset += 8 + size + (size & 1);
+    }
+    let (audio_format, channels, sample_rate, bits) = format.ok_or("missing fmt chunk")?;
+    let data = data.ok_or("missing data chunk")?;
+    if audio_format != 1 {
+        return Err(format!("unsupported audio format {}, only PCM is supported", audio_format));
+    }
+    if channels == 0 {
+        return Err("no channels".to_string());
+    }
+    if sample_rate == 0 {
+        return Err("sample rate of 0".to_string());
+    }
+    let samples = match bits {
+        8 => data.iter().map(|b| ((*b as i16) - 128) << 8).collect(),
+        16 => data.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect(),
+        _ => return Err(format!("unsupported sample size of {} bits", bits))
+    };
+    Ok(Wav {
+        sample_rate,
+        channels,
+        samples
+    })
+}
+
+// Streams 16 bit PCM to a WAV file. The sizes in the header are filled in by
+// `finish`, which also runs when the writer is dropped.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    data_len: u32
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            out,
+            data_len: 0
+        })
+    }
+
+    // Fails without writing anything once the file would pass the 4 GiB
+    // a RIFF header can describe, so what was written stays readable.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let data_len = u32::try_from(samples.len() * 2).ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|data_len| data_len.checked_add(36).is_some())
+            .ok_or_else(|| io::Error::other("the WAV file is full at 4 GiB"))?;
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            println!("Error finishing WAV file {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use crate::wav::{WavWriter, decode};
+
+    #[test]
+    fn written_file_decodes() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 44_100, 2).unwrap();
+        writer.write_samples(&[1, -1, 300, -300]).unwrap();
+        writer.write_samples(&[i16::MAX, i16::MIN]).unwrap();
+        writer.finish().unwrap();
+        let wav = decode(writer.get_ref().get_ref()).unwrap();
+        assert_eq!(wav.sample_rate, 44_100);
+        assert_eq!(wav.channels, 2);
+        assert_eq!(wav.samples, vec![1, -1, 300, -300, i16::MAX, i16::MIN]);
+
+        writer.data_len = u32::MAX - 36 - 4;
+        assert!(writer.write_samples(&[1, 2]).is_ok());
+        assert!(writer.write_samples(&[3]).is_err());
+    }
+}
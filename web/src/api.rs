@@ -0,0 +1,258 @@
+// A machine for pages that bring their own UI: the page loads the ROM, runs
+// the frames, draws the framebuffer and plays the sound, either the samples
+// it's told about or, with `sound = "synth"` (the default), the PCM from
+// `samples`.
+//
+//     const emulator = new Emulator("spaceinvaders");
+//     emulator.loadRom(bytes);
+//     emulator.onSound((event, file) => ...);  // or queue emulator.samples() after each frame
+//     emulator.setButton(1, "Coin", true);
+//     emulator.runFrame();
+//     const pixels = new Uint8ClampedArray(emulator.framebuffer());
+//     context.putImageData(new ImageData(pixels, emulator.width, emulator.height), 0, 0);
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use emulator::config::Config;
+use emulator::overlay::Overlay;
+use emulator::machines::{Speaker, Controller, Button, ButtonState, Player, Machine, MachineInfo, DipValue, Backends, find_machine, create_machine};
+use emulator::frontends::headless::NullScreen;
+use emulator::resources::NoResources;
+
+// Hands the machine's sound events to the page as `callback(event, file)`,
+// where the event is "start" (looped), "stop" or "play" (once), and keeps the
+// frame's PCM when the machine mixes its own sound.
+struct CallbackSpeaker {
+    callback: Rc<RefCell<Option<js_sys::Function>>>,
+    samples: Rc<RefCell<Vec<i16>>>
+}
+
+impl CallbackSpeaker {
+    fn call(&self, event: &str, file_name: &str) {
+        if let Some(callback) = self.callback.borrow().as_ref() {
+            if let Err(e) = callback.call2(&JsValue::NULL, &event.into(), &file_name.into()) {
+                web_sys::console::error_1(&e);
+            }
+        }
+    }
+}
+
+impl Speaker for CallbackSpeaker {
+    fn start_wav_file(&mut self, file_name: &str) {
+        self.call("start", file_name);
+    }
+    fn stop_wav_file(&mut self, file_name: &str) {
+        self.call("stop", file_name);
+    }
+    fn play_wav_file(&mut self, file_name: &str) {
+        self.call("play", file_name);
+    }
+    fn queue_samples(&mut self, samples: &[i16]) {
+        self.samples.borrow_mut().extend_from_slice(samples);
+    }
+}
+
+// Buttons set by the page since the last frame.
+#[derive(Default)]
+struct ButtonQueue {
+    buttons: Vec<(Player, Button)>
+}
+
+impl Controller for ButtonQueue {
+    fn get_button_states(&mut self) -> Vec<(Player, Button)> {
+        std::mem::take(&mut self.buttons)
+    }
+}
+
+#[wasm_bindgen]
+pub struct Emulator {
+    config: Config,
+    info: MachineInfo,
+    machine: Option<Box<dyn Machine>>,
+    overlay: Overlay,
+    sound_callback: Rc<RefCell<Option<js_sys::Function>>>,
+    samples: Rc<RefCell<Vec<i16>>>,
+    buttons: Rc<RefCell<ButtonQueue>>,
+    // RGBA, what `framebuffer` views
+    rgba: Vec<u8>
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    // `machine` defaults to Space Invaders and `config` is the TOML of a
+    // native config file, for the DIP switches, overlay and so on.
+    #[wasm_bindgen(constructor)]
+    pub fn new(machine: Option<String>, config: Option<String>) -> Result<Emulator, String> {
+        crate::utils::set_panic_hook();
+        let mut config = match config {
+            Some(text) => Config::from_toml(&text)?,
+            None => Config::default()
+        };
+        if let Some(machine) = machine {
+            config.machine = machine;
+        }
+        let info = find_machine(&config.machine)?;
+        let (width, height) = info.screen_size(config.orientation);
+        Ok(Emulator {
+            overlay: config.screen_overlay()?,
+            config,
+            info,
+            machine: None,
+            sound_callback: Rc::new(RefCell::new(None)),
+            samples: Rc::new(RefCell::new(Vec::new())),
+            buttons: Rc::new(RefCell::new(ButtonQueue::default())),
+            rgba: vec![0; width * height * 4]
+        })
+    }
+
+    // Starts the machine from power on with `bytes` as its ROM. A ROM that
+    // doesn't match the machine's is run anyway after a warning, as it may
+    // be patched.
+    #[wasm_bindgen(js_name = loadRom)]
+    pub fn load_rom(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if let Err(e) = self.info.check_rom(bytes) {
+            web_sys::console::warn_1(&e.into());
+        }
+        let backends = Backends {
+            speaker: Box::new(CallbackSpeaker { callback: self.sound_callback.clone(), samples: self.samples.clone() }),
+            screen: Box::new(NullScreen),
+            controller: Box::new(self.buttons.clone()),
+            // the page plays the samples, the synthesizer gets a mixer anyway
+            mixer: None,
+            resources: Box::new(NoResources)
+        };
+        let machine = create_machine(self.info.name, bytes.to_vec(), backends, &self.config)?;
+        let (width, height) = machine.screen_size();
+        self.rgba = vec![0; width * height * 4];
+        self.machine = Some(machine);
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = runFrame)]
+    pub fn run_frame(&mut self) -> Result<(), String> {
+        let machine = self.machine.as_mut().ok_or("no ROM loaded")?;
+        self.samples.borrow_mut().clear();
+        machine.run_next_frame();
+        let mut frame = machine.frame_rgb();
+        self.overlay.tint_rgb(&mut frame, machine.screen_size().0);
+        for (rgba, rgb) in self.rgba.chunks_exact_mut(4).zip(frame.chunks_exact(3)) {
+            rgba.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 0xff]);
+        }
+        Ok(())
+    }
+
+    // The screen after the last frame as `width` x `height` RGBA. This is a
+    // view into the wasm memory, so copy it or use it before calling back
+    // into the emulator.
+    pub fn framebuffer(&self) -> js_sys::Uint8Array {
+        // safe as long as the view isn't kept past the next allocation
+        unsafe { js_sys::Uint8Array::view(&self.rgba) }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> usize {
+        self.info.screen_size(self.config.orientation).0
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> usize {
+        self.info.screen_size(self.config.orientation).1
+    }
+
+    #[wasm_bindgen(getter, js_name = refreshRate)]
+    pub fn refresh_rate(&self) -> u32 {
+        self.info.refresh_rate
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn title(&self) -> String {
+        self.info.title.to_string()
+    }
+
+    // The machine's buttons as `{player, button, label}`, e.g.
+    // `{player: 1, button: "Shoot", label: "Fire"}`.
+    pub fn inputs(&self) -> js_sys::Array {
+        self.info.inputs.iter().map(|input| {
+            let object = js_sys::Object::new();
+            let player = match input.player {
+                Player::Player1 => 1,
+                Player::Player2 => 2
+            };
+            let _ = js_sys::Reflect::set(&object, &"player".into(), &player.into());
+            let _ = js_sys::Reflect::set(&object, &"button".into(), &format!("{:?}", input.button).into());
+            let _ = js_sys::Reflect::set(&object, &"label".into(), &input.label.into());
+            JsValue::from(object)
+        }).collect()
+    }
+
+    // `player` is 1 or 2 and `button` one of the names from `inputs`. Takes
+    // effect on the next frame.
+    #[wasm_bindgen(js_name = setButton)]
+    pub fn set_button(&mut self, player: u8, button: &str, pressed: bool) -> Result<(), String> {
+        let player = match player {
+            1 => Player::Player1,
+            2 => Player::Player2,
+            _ => return Err(format!("player {} should be 1 or 2", player))
+        };
+        let input = self.info.inputs.iter()
+            .find(|input| input.player == player && format!("{:?}", input.button) == button)
+            .ok_or_else(|| format!("{} has no {} button for player {:?}", self.info.title, button, player))?;
+        let state = if pressed { ButtonState::Down } else { ButtonState::Up };
+        self.buttons.borrow_mut().buttons.push((player, input.button.with_state(state)));
+        Ok(())
+    }
+
+    // Restarts the program like the cabinet's reset button, picking up the
+    // DIP switches set since.
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.machine.as_mut().ok_or("no ROM loaded")?.reset();
+        Ok(())
+    }
+
+    // A flag or a number by the name in the `[dip_switches]` table, e.g.
+    // `setDipSwitch("lives", 5)`. Like the real board, the game only reads
+    // them on the next `reset` or `loadRom`.
+    #[wasm_bindgen(js_name = setDipSwitch)]
+    pub fn set_dip_switch(&mut self, name: &str, value: JsValue) -> Result<(), String> {
+        let value = match (value.as_bool(), value.as_f64()) {
+            (Some(flag), _) => DipValue::Flag(flag),
+            (None, Some(number)) if number.fract() == 0.0 && (0.0..=u16::MAX as f64).contains(&number) => {
+                DipValue::Number(number as u16)
+            }
+            _ => return Err(format!("{} needs true, false or a whole number", name))
+        };
+        let mut dip_switches = self.config.dip_switches.clone();
+        dip_switches.insert(name.to_string(), value);
+        self.info.check_dip_switches(&dip_switches)?;
+        self.config.dip_switches = dip_switches;
+        if let Some(machine) = self.machine.as_mut() {
+            machine.set_dip_switches(&self.config.dip_switches);
+        }
+        Ok(())
+    }
+
+    // In the same format as the native save states, so they can be moved
+    // between the two.
+    #[wasm_bindgen(js_name = saveState)]
+    pub fn save_state(&self) -> Result<Vec<u8>, String> {
+        Ok(self.machine.as_ref().ok_or("no ROM loaded")?.save_state())
+    }
+
+    #[wasm_bindgen(js_name = loadState)]
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.machine.as_mut().ok_or("no ROM loaded")?.load_state(bytes)
+    }
+
+    // The last frame's sound as interleaved stereo at 44100Hz, for the page to
+    // queue up for a worklet or script processor. Empty when the page plays
+    // the samples itself.
+    pub fn samples(&self) -> Vec<i16> {
+        self.samples.borrow().clone()
+    }
+
+    // `callback(event, file)` for each sound, see `CallbackSpeaker`.
+    #[wasm_bindgen(js_name = onSound)]
+    pub fn on_sound(&mut self, callback: Option<js_sys::Function>) {
+        *self.sound_callback.borrow_mut() = callback;
+    }
+}
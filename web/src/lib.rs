@@ -1,17 +1,17 @@
 mod utils;
-use std::f64;
-use wasm_bindgen::prelude::*;
-use wasm_bindgen::JsCast;
-use emulator::cpu::Cpu;
-use web_sys::{Window, Request, RequestInit, RequestMode, Response, KeyboardEvent};
-use std::fs;
-use emulator::machines::IO;
-use emulator::machines::Speaker;
-use emulator::machines::spaceinvaders::{SpaceInvadersIO, SpaceInvaders};
-use emulator::machines::{Controller, Player, ButtonState, Button};
-use wasm_bindgen_futures::JsFuture;
-use std::rc::Rc;
+mod api;
+mod storage;
 use std::cell::RefCell;
+use std::future::Future;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use emulator::config::Config;
+use emulator::machines::{Machine, MachineInfo, DipValue, find_machine};
+use emulator::frontends::web::{WebFrontend, WebAudio, load_resources, load_rom};
+pub use crate::api::Emulator;
+
+// Relative to the page, so the site works wherever it's hosted.
+const DEFAULT_RESOURCES_URL: &str = "../resources/";
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -19,45 +19,197 @@ use std::cell::RefCell;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+// The DIP switch arguments are optional, anything left undefined keeps the
+// saved setting. `resources_url` is where the ROM and samples are fetched
+// from, unless they're built in. `rom` is a ROM the player chose, which is
+// checked and remembered for their next visit; without it the remembered
+// one is used, or else the one from `resources_url`.
+//
+// Resolves to the `Game` once it's running, or rejects with a message for
+// the player.
+#[wasm_bindgen]
+pub async fn start_spaceinvaders(
+    lives: Option<u8>,
+    bonus_life: Option<u16>,
+    coin_info: Option<bool>,
+    resources_url: Option<String>,
+    rom: Option<Vec<u8>>
+) -> Result<Game, String> {
+    utils::set_panic_hook();
+    let mut config = storage::load_settings();
+    let dip_switches = [
+        ("lives", lives.map(|lives| DipValue::Number(lives as u16))),
+        ("bonus_life", bonus_life.map(DipValue::Number)),
+        ("coin_info", coin_info.map(DipValue::Flag))
+    ];
+    for (name, value) in dip_switches {
+        if let Some(value) = value {
+            config.dip_switches.insert(name.to_string(), value);
+        }
+    }
+    find_machine(&config.machine)?.check_dip_switches(&config.dip_switches)?;
+    start(&config, resources_url.as_deref().unwrap_or(DEFAULT_RESOURCES_URL), rom).await
+}
+
+// The saved settings as the TOML of a native config file, the defaults if
+// nothing has been saved. They apply from the next start.
+#[wasm_bindgen]
+pub fn settings() -> String {
+    storage::load_settings().to_toml()
+}
+
 #[wasm_bindgen]
-extern {
-    fn alert(s: &str);
+pub fn save_settings(text: &str) -> Result<(), String> {
+    storage::save_settings(&Config::from_toml(text)?)
 }
 
+// The ROM's chips in the order they're loaded, so files picked one per chip
+// can be put together.
 #[wasm_bindgen]
-pub fn greet() {
-    alert("Hello, web!");
+pub fn rom_chips(machine: Option<String>) -> Result<Vec<String>, String> {
+    let info = find_machine(machine.as_deref().unwrap_or(&Config::default().machine))?;
+    Ok(info.roms.iter().map(|rom| rom.name.to_string()).collect())
 }
 
-struct WebSpeaker {}
-impl Speaker for WebSpeaker {
-    fn start_wav_file(&mut self, file_name: &str) {
+async fn start(config: &Config, resources_url: &str, rom: Option<Vec<u8>>) -> Result<Game, String> {
+    let info = find_machine(&config.machine)?;
+    let bytes = match rom {
+        Some(bytes) => {
+            info.check_rom(&bytes).map_err(|e| format!("{}. Try another file.", e))?;
+            if let Err(e) = storage::save(storage::ROMS, info.name, &bytes).await {
+                web_sys::console::warn_1(&format!("The ROM won't be remembered: {}", e).into());
+            }
+            bytes
+        }
+        None => remembered_rom(&info, resources_url).await?
+    };
+    let resources = load_resources(resources_url, &info, config).await?;
+    let frontend = WebFrontend::new(bytes, config, resources)
+        .map_err(|e| format!("{} couldn't start: {}.", info.title, e))?;
+    let game = Game {
+        name: info.name,
+        machine: frontend.machine(),
+        audio: frontend.audio()
+    };
+    frontend.play();
+    Ok(game)
+}
 
+async fn remembered_rom(info: &MachineInfo, resources_url: &str) -> Result<Vec<u8>, String> {
+    match storage::load(storage::ROMS, info.name).await {
+        Ok(Some(bytes)) => return Ok(bytes),
+        Ok(None) => {}
+        Err(e) => web_sys::console::warn_1(&format!("No remembered ROM: {}", e).into())
     }
-    fn stop_wav_file(&mut self, file_name: &str) {
+    let bytes = load_rom(resources_url, info).await
+        .map_err(|e| format!("The {} ROM couldn't be downloaded ({}). Choose the ROM file or drop it on the page.", info.title, e))?;
+    // the site's own copy may be patched
+    if let Err(e) = info.check_rom(&bytes) {
+        web_sys::console::warn_1(&e.into());
+    }
+    Ok(bytes)
+}
 
+// The running game, for the page's volume and mute controls and save
+// states. Save states are kept in slots named by the page, and exported in
+// the same format as the native save states.
+#[wasm_bindgen]
+pub struct Game {
+    name: &'static str,
+    machine: Rc<RefCell<Box<dyn Machine>>>,
+    audio: WebAudio
+}
+
+#[wasm_bindgen]
+impl Game {
+    // 0 (silent) to 100, saved with the settings
+    #[wasm_bindgen(getter)]
+    pub fn volume(&self) -> u8 {
+        self.audio.volume()
     }
-    fn play_wav_file(&mut self, file_name: &str) {
 
+    #[wasm_bindgen(setter)]
+    pub fn set_volume(&self, volume: u8) {
+        self.audio.set_volume(volume);
+        let config = Config { volume: self.audio.volume(), ..storage::load_settings() };
+        if let Err(e) = storage::save_settings(&config) {
+            web_sys::console::warn_1(&e.into());
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn muted(&self) -> bool {
+        self.audio.is_muted()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_muted(&self, muted: bool) {
+        self.audio.set_muted(muted);
+    }
+
+    // Resolves once the state is stored in `slot`, replacing what was there.
+    #[wasm_bindgen(js_name = saveState)]
+    pub fn save_state(&self, slot: String) -> js_sys::Promise {
+        let bytes = self.machine.borrow().save_state();
+        let key = self.slot_key(&slot);
+        promise(async move {
+            storage::save(storage::STATES, &key, &bytes).await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    #[wasm_bindgen(js_name = loadState)]
+    pub fn load_state(&self, slot: String) -> js_sys::Promise {
+        let machine = self.machine.clone();
+        let key = self.slot_key(&slot);
+        promise(async move {
+            let bytes = storage::load(storage::STATES, &key).await?.ok_or_else(|| format!("nothing saved in {}", slot))?;
+            machine.borrow_mut().load_state(&bytes)?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    // Resolves to the names of the slots with something saved in them.
+    #[wasm_bindgen(js_name = listStates)]
+    pub fn list_states(&self) -> js_sys::Promise {
+        let prefix = self.slot_key("");
+        promise(async move {
+            let slots = storage::list(storage::STATES).await?.into_iter()
+                .filter_map(|key| key.strip_prefix(&prefix).map(JsValue::from))
+                .collect::<js_sys::Array>();
+            Ok(slots.into())
+        })
+    }
+
+    #[wasm_bindgen(js_name = deleteState)]
+    pub fn delete_state(&self, slot: String) -> js_sys::Promise {
+        let key = self.slot_key(&slot);
+        promise(async move {
+            storage::delete(storage::STATES, &key).await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    // The state as a file the native build loads with --load-state.
+    #[wasm_bindgen(js_name = exportState)]
+    pub fn export_state(&self) -> Vec<u8> {
+        self.machine.borrow().save_state()
+    }
+
+    // A state from `exportState` or saved by the native build.
+    #[wasm_bindgen(js_name = importState)]
+    pub fn import_state(&self, bytes: &[u8]) -> Result<(), String> {
+        self.machine.borrow_mut().load_state(bytes)
+    }
+
+    fn slot_key(&self, slot: &str) -> String {
+        format!("{}/{}", self.name, slot)
     }
 }
 
-#[wasm_bindgen]
-pub async fn start_spaceinvaders() {
-    let window = web_sys::window().unwrap();
-
-    let url = "/eightyeighty/resources/spaceinvaders/invaders";
-    let mut opts = RequestInit::new();
-    opts.method("GET");
-    let request = Request::new_with_str_and_init(&url, &opts).unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await.unwrap();
-    let resp: Response = resp_value.dyn_into().unwrap();
-    let buffer = JsFuture::from(resp.array_buffer().unwrap()).await.unwrap();
-    let typed_buf : js_sys::Uint8Array = js_sys::Uint8Array::new(&buffer);
-
-    let mut bytes = vec![0; typed_buf.length() as usize];
-    typed_buf.copy_to(&mut bytes);
-   
-    let space_invdaers = SpaceInvaders::new(bytes);
-    space_invdaers.play();
-}
\ No newline at end of file
+// Rejects with the error message, so the page can show it.
+fn promise(future: impl Future<Output = Result<JsValue, String>> + 'static) -> js_sys::Promise {
+    wasm_bindgen_futures::future_to_promise(async move {
+        future.await.map_err(JsValue::from)
+    })
+}
@@ -0,0 +1,84 @@
+// Files kept in the browser's IndexedDB between visits, in one object store
+// per kind of file and keyed by name.
+const DATABASE = 'eightyeighty';
+const VERSION = 2;
+const STORES = ['roms', 'states'];
+
+function request(request) {
+    return new Promise((resolve, reject) => {
+        request.onsuccess = () => resolve(request.result);
+        request.onerror = () => reject(request.error);
+    });
+}
+
+// The connection is opened once and shared. It's closed when another tab
+// upgrades the database, and opened again on the next use.
+let database = null;
+
+function open() {
+    if (database === null) {
+        database = new Promise((resolve, reject) => {
+            const opening = indexedDB.open(DATABASE, VERSION);
+            opening.onupgradeneeded = () => {
+                for (const store of STORES) {
+                    if (!opening.result.objectStoreNames.contains(store)) {
+                        opening.result.createObjectStore(store);
+                    }
+                }
+            };
+            let blocked = false;
+            opening.onblocked = () => {
+                blocked = true;
+                reject(new Error('the saved files are in use by an older version of the page in another tab'));
+            };
+            opening.onsuccess = () => {
+                const opened = opening.result;
+                // too late, the caller has been told it failed
+                if (blocked) {
+                    opened.close();
+                    return;
+                }
+                opened.onversionchange = () => {
+                    opened.close();
+                    database = null;
+                };
+                resolve(opened);
+            };
+            opening.onerror = () => reject(opening.error);
+        });
+        database.catch(() => database = null);
+    }
+    return database;
+}
+
+async function objectStore(store, mode) {
+    return (await open()).transaction(store, mode).objectStore(store);
+}
+
+export async function getFile(store, key) {
+    return request((await objectStore(store, 'readonly')).get(key));
+}
+
+// A write has only happened once its transaction commits; the request can
+// succeed and the transaction still abort, e.g. when the disk is full.
+async function write(store, change) {
+    const transaction = (await open()).transaction(store, 'readwrite');
+    return new Promise((resolve, reject) => {
+        transaction.oncomplete = () => resolve();
+        transaction.onerror = () => reject(transaction.error);
+        transaction.onabort = () => reject(transaction.error || new Error('the write was cancelled'));
+        change(transaction.objectStore(store));
+    });
+}
+
+export async function putFile(store, key, bytes) {
+    await write(store, files => files.put(bytes, key));
+}
+
+export async function deleteFile(store, key) {
+    await write(store, files => files.delete(key));
+}
+
+export async function listFiles(store) {
+    return request((await objectStore(store, 'readonly')).getAllKeys());
+}
@@ -0,0 +1,75 @@
+// Files kept in the browser between visits, see storage.js, and the
+// settings, kept in local storage as the TOML of a native config file.
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use emulator::config::Config;
+
+#[wasm_bindgen(module = "/src/storage.js")]
+extern "C" {
+    #[wasm_bindgen(catch, js_name = getFile)]
+    async fn get_file(store: &str, key: &str) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(catch, js_name = putFile)]
+    async fn put_file(store: &str, key: &str, bytes: js_sys::Uint8Array) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(catch, js_name = deleteFile)]
+    async fn delete_file(store: &str, key: &str) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(catch, js_name = listFiles)]
+    async fn list_files(store: &str) -> Result<JsValue, JsValue>;
+}
+
+pub const ROMS: &str = "roms";
+// keyed by machine and slot, e.g. spaceinvaders/quick
+pub const STATES: &str = "states";
+
+const SETTINGS_KEY: &str = "eightyeighty.toml";
+
+fn describe(e: JsValue) -> String {
+    e.dyn_ref::<js_sys::Error>()
+        .map(|e| String::from(e.message()))
+        .or_else(|| e.as_string())
+        .unwrap_or_else(|| "the browser's storage isn't available".to_string())
+}
+
+pub async fn load(store: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+    let value = get_file(store, key).await.map_err(describe)?;
+    Ok(value.dyn_into::<js_sys::Uint8Array>().ok().map(|bytes| bytes.to_vec()))
+}
+
+pub async fn save(store: &str, key: &str, bytes: &[u8]) -> Result<(), String> {
+    put_file(store, key, js_sys::Uint8Array::from(bytes)).await.map_err(describe)?;
+    Ok(())
+}
+
+pub async fn delete(store: &str, key: &str) -> Result<(), String> {
+    delete_file(store, key).await.map_err(describe)?;
+    Ok(())
+}
+
+pub async fn list(store: &str) -> Result<Vec<String>, String> {
+    let keys: js_sys::Array = list_files(store).await.map_err(describe)?.unchecked_into();
+    Ok(keys.iter().filter_map(|key| key.as_string()).collect())
+}
+
+fn local_storage() -> Result<web_sys::Storage, String> {
+    web_sys::window()
+        .and_then(|window| window.local_storage().ok().flatten())
+        .ok_or_else(|| "the browser's local storage isn't available".to_string())
+}
+
+// The saved settings, or the defaults if there are none or they don't parse.
+pub fn load_settings() -> Config {
+    let text = local_storage().ok().and_then(|storage| storage.get_item(SETTINGS_KEY).ok().flatten());
+    match text.map(|text| Config::from_toml(&text)) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            web_sys::console::warn_1(&format!("Ignoring the saved settings: {}", e).into());
+            Config::default()
+        }
+        None => Config::default()
+    }
+}
+
+pub fn save_settings(config: &Config) -> Result<(), String> {
+    local_storage()?
+        .set_item(SETTINGS_KEY, &config.to_toml())
+        .map_err(|_| "the settings couldn't be saved, the browser's storage may be full".to_string())
+}